@@ -1,21 +1,35 @@
 use std::fmt;
+use std::fs;
+use std::collections::HashSet;
 use std::{ thread, time };
-use std::sync::{ Arc, RwLock };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
 use url::Url;
 use serde::{ Serialize, Deserialize };
+use serde::de::DeserializeOwned;
 use reqwest::header::{ HeaderName, HeaderValue };
+use ed25519_dalek::{ PublicKey, Signature };
+use chrono::{ DateTime, Utc };
 use crate::crypto::Signer;
-use crate::peripherals::Notifier;
+use crate::peripherals::{ self, Notifier, ToneTheme };
+use crate::feedback;
+use crate::releases::Release;
 
 pub enum Error {
 	Network(reqwest::Error),
 	Message(&'static str),
+	/// We were rejected or deleted in the manager UI
+	Revoked,
+	/// Response wasn't signed by the pinned manager key, so it might not have come from the manager
+	BadSignature,
 }
 impl fmt::Debug for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			Error::Network(err) => write!(f, "{:?}", err),
 			Error::Message(s) => write!(f, "{}", s),
+			Error::Revoked => write!(f, "Access revoked in manager UI"),
+			Error::BadSignature => write!(f, "Response not signed by the manager"),
 		}
 	}
 }
@@ -36,6 +50,48 @@ struct SignedRequest {
 	header_value: HeaderValue,
 }
 
+// Sent with a random value on every request. The manager signs its response along with it so that an old response can't be replayed.
+const CHALLENGE_HEADER: &'static str = "X-Checkin-Challenge";
+const SIGNATURE_HEADER: &'static str = "X-Manager-Signature";
+
+/// The request a response was for. Covered by the response signature so that a response can't be passed off as the answer to a different request.
+struct SentRequest {
+	challenge: String,
+	method: String,
+	/// Path and query
+	path: String,
+	/// Hex SHA-256 of the request body
+	body_hash: String,
+}
+
+// Must match `response_message` in the server
+fn response_message(request: &SentRequest, status: u16, body: &[u8]) -> Vec<u8> {
+	let mut message = format!("checkin-embedded response {} {} {} {} {}\n", request.challenge, request.method, request.path, request.body_hash, status).into_bytes();
+	message.extend_from_slice(body);
+	message
+}
+
+/// A response that hasn't been checked against the manager's key yet
+struct ChallengedResponse {
+	request: SentRequest,
+	status: reqwest::StatusCode,
+	body: Vec<u8>,
+	signature: Option<Signature>,
+}
+
+impl ChallengedResponse {
+	fn verify(self, server_key: &PublicKey) -> Result<(reqwest::StatusCode, Vec<u8>), Error> {
+		let signature = self.signature.ok_or(Error::BadSignature)?;
+		server_key.verify(&response_message(&self.request, self.status.as_u16(), &self.body), &signature)
+			.map_err(|_| Error::BadSignature)?;
+		Ok((self.status, self.body))
+	}
+}
+
+fn parse_response<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+	serde_json::from_slice(body).map_err(|_| Error::Message("Invalid response from manager"))
+}
+
 #[derive(Deserialize, PartialEq, Debug)]
 pub enum ManagedStatus {
 	Pending,
@@ -43,6 +99,78 @@ pub enum ManagedStatus {
 	AuthorizedHasCredentials,
 	AuthorizedNoCredentials,
 }
+/// Data relayed by the manager along with the manager's signature over it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedData {
+	pub data: String,
+	pub signature: String,
+}
+
+impl SignedData {
+	pub fn verify(&self, key: &PublicKey) -> bool {
+		let signature = match hex::decode(&self.signature).ok().and_then(|signature| Signature::from_bytes(&signature).ok()) {
+			Some(signature) => signature,
+			None => return false,
+		};
+		key.verify(self.data.as_bytes(), &signature).is_ok()
+	}
+}
+
+/// Per-device settings managed from the manager UI
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceSettings {
+	pub brightness: u8,
+	pub scroll_speed: u64,
+	pub buzzer_enabled: bool,
+	pub tone_theme: ToneTheme,
+	/// Overrides `poll_interval` from checkin.toml when set
+	pub poll_interval: Option<u64>,
+	pub idle_message: Option<String>,
+}
+
+// The manager's setting wins when there is one, otherwise `configured` from checkin.toml is kept
+fn effective_poll_interval(settings: Option<&DeviceSettings>, configured: u64) -> u64 {
+	settings.and_then(|settings| settings.poll_interval).unwrap_or(configured)
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CommandKind {
+	Identify,
+	Reboot,
+	ShowMessage,
+	RefetchCredentials,
+	RotateKey,
+}
+
+/// A command sent from the manager UI. Only run once its signature has been checked.
+#[derive(Deserialize, Debug)]
+pub struct Command {
+	pub id: String,
+	/// Commands are signed for a specific device so that one can't be replayed to another
+	pub device: String,
+	pub command: CommandKind,
+	pub message: Option<String>,
+	pub issued_at: DateTime<Utc>,
+}
+
+impl Command {
+	// Must match DeviceCommand::EXPIRY_MINUTES in the server
+	const EXPIRY_MINUTES: i64 = 10;
+
+	/// The manager stops sending commands after this so an old one can only be a replay
+	fn is_expired(&self, now: DateTime<Utc>) -> bool {
+		now.signed_duration_since(self.issued_at).num_minutes() >= Command::EXPIRY_MINUTES
+	}
+}
+
+/// Everything the manager pushes to us over the long-poll
+pub struct Update {
+	pub version: String,
+	pub tag: Option<String>,
+	pub settings: Option<DeviceSettings>,
+	pub commands: Vec<SignedData>,
+}
+
 #[derive(Debug)]
 pub struct CredentialResponse {
 	pub success: bool,
@@ -54,37 +182,70 @@ pub struct CredentialResponse {
 pub struct ManagerAPI {
 	base_url: Url,
 	client: reqwest::Client,
+	// Separate client with a longer timeout for requests that the server intentionally holds open
+	long_poll_client: reqwest::Client,
 	signer: Signer,
+	// Manager's signing key, pinned the first time we talk to it
+	server_key: Arc<RwLock<Option<PublicKey>>>,
+	started: time::Instant,
 	pub current_tag: Arc<RwLock<Option<String>>>,
+	pub reader_connected: Arc<AtomicBool>,
+	// Set once the manager tells us we've been rejected or deleted so that taps are turned away
+	revoked: Arc<AtomicBool>,
+	// Seconds between tag checks from checkin.toml, used unless the manager's settings override it
+	poll_interval: Arc<AtomicU64>,
+	settings: Arc<Mutex<Option<DeviceSettings>>>,
+	// Newest client build the manager has told us about, picked up by the updater
+	offered_release: Arc<Mutex<Option<Release>>>,
 }
 
-impl ManagerAPI {
-	#[cfg(debug_assertions)]
-	fn base_url() -> &'static str {
-		"http://192.168.1.15:3000"
-	}
-	#[cfg(not(debug_assertions))]
-	fn base_url() -> &'static str {
-		"https://manager.checkin.hack.gt"
-	}
+const SERVER_KEY_FILE: &'static str = "./manager.pub";
 
-	pub fn new() -> Self {
+impl ManagerAPI {
+	/// `manager_key` comes from `load_server_key`. Without one, the key the manager sends the first time we reach it is pinned.
+	pub fn new(base_url: &str, signer: Signer, manager_key: Option<PublicKey>) -> Self {
 		let client = reqwest::Client::builder()
 			.use_rustls_tls()
 			.timeout(Some(time::Duration::from_secs(10)))
 			.build()
 			.expect("Failed to construct client");
-		let base_url = Url::parse(ManagerAPI::base_url()).expect("Invalid base URL configured");
+		let long_poll_client = reqwest::Client::builder()
+			.use_rustls_tls()
+			.timeout(Some(time::Duration::from_secs(60)))
+			.build()
+			.expect("Failed to construct client");
+		let base_url = Url::parse(base_url).expect("Invalid base URL configured");
 		Self {
 			base_url,
 			client,
-			signer: Signer::load(),
+			long_poll_client,
+			signer,
+			server_key: Arc::new(RwLock::new(manager_key)),
+			started: time::Instant::now(),
 			current_tag: Arc::new(RwLock::new(None)),
+			reader_connected: Arc::new(AtomicBool::new(false)),
+			revoked: Arc::new(AtomicBool::new(false)),
+			poll_interval: Arc::new(AtomicU64::new(30)),
+			settings: Arc::new(Mutex::new(None)),
+			offered_release: Arc::new(Mutex::new(None)),
 		}
 	}
 
 	fn sign_request<T: Serialize + ?Sized>(&self, request: &T) -> SignedRequest {
-		let body = serde_json::to_string_pretty(request).expect("Could not serialize object for signing");
+		// The timestamp and nonce are covered by the signature so the server can reject replayed requests
+		#[derive(Serialize)]
+		struct Body<'a, T: Serialize + ?Sized> {
+			timestamp: u64,
+			nonce: String,
+			#[serde(flatten)]
+			request: &'a T,
+		}
+		let body = Body {
+			timestamp: time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs(),
+			nonce: hex::encode(rand::random::<[u8; 16]>()),
+			request,
+		};
+		let body = serde_json::to_string_pretty(&body).expect("Could not serialize object for signing");
 
 		let signature = self.signer.sign(body.as_bytes());
 		let header_value = format!("ed25519 {}/{}", hex::encode(&self.signer.get_public_key()), hex::encode(&signature.to_bytes()[..]));
@@ -96,6 +257,93 @@ impl ManagerAPI {
 		}
 	}
 
+	/// The manager's key from the config, which has already been validated
+	pub fn configured_server_key(configured: Option<&str>) -> Option<PublicKey> {
+		configured.map(|key| {
+			let key = hex::decode(key).expect("Invalid manager key");
+			PublicKey::from_bytes(&key).expect("Invalid manager key")
+		})
+	}
+
+	/// The manager's key from the config or the one pinned earlier, if either exists
+	pub fn load_server_key(configured: Option<&str>) -> Result<Option<PublicKey>, String> {
+		if let Some(key) = ManagerAPI::configured_server_key(configured) {
+			return Ok(Some(key));
+		}
+		let key = match fs::read(SERVER_KEY_FILE) {
+			Ok(key) => key,
+			Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(format!("Could not read {}: {}", SERVER_KEY_FILE, err)),
+		};
+		PublicKey::from_bytes(&key)
+			.map(Some)
+			.map_err(|_| format!("{} is corrupt, delete it to pin the manager's key again", SERVER_KEY_FILE))
+	}
+
+	// Trust on first use: remember the key the manager gives us so that later data can be checked against it
+	fn pin_server_key(&self, key: PublicKey) {
+		let mut server_key = self.server_key.write().unwrap();
+		if server_key.is_some() {
+			return;
+		}
+		fs::write(SERVER_KEY_FILE, &key.to_bytes()[..]).expect("Error writing manager key to file");
+		*server_key = Some(key);
+	}
+
+	/// Sends `signed_request` (or nothing for a GET) to `path`, which can include a query
+	fn send_challenged(&self, client: &reqwest::Client, method: reqwest::Method, path: &str, signed_request: Option<SignedRequest>) -> Result<ChallengedResponse, Error> {
+		let url = self.base_url.join(path).unwrap();
+		let challenge = hex::encode(rand::random::<[u8; 16]>());
+		let body = signed_request.as_ref().map(|signed_request| signed_request.body.as_bytes()).unwrap_or(b"");
+		let sent = SentRequest {
+			challenge: challenge.clone(),
+			method: method.as_str().to_owned(),
+			path: match url.query() {
+				Some(query) => format!("{}?{}", url.path(), query),
+				None => url.path().to_owned(),
+			},
+			body_hash: crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, body),
+		};
+
+		let mut request = client.request(method, url)
+			.header(CHALLENGE_HEADER, challenge.as_str());
+		if let Some(signed_request) = signed_request {
+			request = request
+				.header(signed_request.header_name, signed_request.header_value)
+				.header(reqwest::header::CONTENT_TYPE, HeaderValue::from_static("application/json"))
+				.body(signed_request.body);
+		}
+		let mut response = request.send()?;
+		let signature = response.headers().get(SIGNATURE_HEADER)
+			.and_then(|signature| signature.to_str().ok())
+			.and_then(|signature| hex::decode(signature).ok())
+			.and_then(|signature| Signature::from_bytes(&signature).ok());
+		let mut body = Vec::new();
+		response.copy_to(&mut body)?;
+		Ok(ChallengedResponse { request: sent, status: response.status(), body, signature })
+	}
+
+	/// Sends a request and returns the response once its signature has been checked against the pinned manager key
+	fn send(&self, client: &reqwest::Client, method: reqwest::Method, path: &str, signed_request: Option<SignedRequest>) -> Result<(reqwest::StatusCode, Vec<u8>), Error> {
+		let response = self.send_challenged(client, method, path, signed_request)?;
+		let server_key = *self.server_key.read().unwrap();
+		let server_key = server_key.ok_or(Error::Message("Manager key isn't known yet"))?;
+		response.verify(&server_key)
+	}
+
+	fn post_json<T: DeserializeOwned>(&self, client: &reqwest::Client, path: &str, signed_request: SignedRequest) -> Result<T, Error> {
+		let (_, body) = self.send(client, reqwest::Method::POST, path, Some(signed_request))?;
+		parse_response(&body)
+	}
+
+	/// Checks that data relayed through the manager was signed by the pinned manager key
+	pub fn verify_signed_data(&self, signed: &SignedData) -> bool {
+		match self.server_key.read().unwrap().as_ref() {
+			Some(key) => signed.verify(key),
+			None => false,
+		}
+	}
+
 	pub fn get_name(&self) -> String {
 		crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &self.signer.get_public_key())
 	}
@@ -108,18 +356,30 @@ impl ManagerAPI {
 		#[derive(Deserialize)]
 		struct Response {
 			status: ManagedStatus,
+			server_key: Option<String>,
 		}
 		let request = Request {
 			username: &self.get_name(),
 		};
 		let signed_request = self.sign_request(&request);
 
-		let response: Response = self.client.post(self.base_url.join("/api/initialize").unwrap())
-			.header(signed_request.header_name, signed_request.header_value)
-			.header(reqwest::header::CONTENT_TYPE, HeaderValue::from_static("application/json"))
-			.body(signed_request.body)
-			.send()?
-			.json()?;
+		let response = self.send_challenged(&self.client, reqwest::Method::POST, "/api/initialize", Some(signed_request))?;
+		let pinned_key = *self.server_key.read().unwrap();
+		let server_key = match pinned_key {
+			Some(key) => key,
+			None => {
+				// First contact so the response can only be checked against the key it contains
+				let unverified: Response = parse_response(&response.body)?;
+				let key = unverified.server_key.ok_or(Error::Message("Manager didn't send its key"))?;
+				let key = hex::decode(key).map_err(|_| Error::Message("Manager sent an invalid key"))?;
+				PublicKey::from_bytes(&key).map_err(|_| Error::Message("Manager sent an invalid key"))?
+			},
+		};
+		let (_, body) = response.verify(&server_key)?;
+		let response: Response = parse_response(&body)?;
+		if pinned_key.is_none() {
+			self.pin_server_key(server_key);
+		}
 		Ok(response.status)
 	}
 
@@ -142,12 +402,7 @@ impl ManagerAPI {
 		};
 		let signed_request = self.sign_request(&request);
 
-		let response: Response = self.client.post(self.base_url.join("/api/credentials").unwrap())
-			.header(signed_request.header_name, signed_request.header_value)
-			.header(reqwest::header::CONTENT_TYPE, HeaderValue::from_static("application/json"))
-			.body(signed_request.body)
-			.send()?
-			.json()?;
+		let response: Response = self.post_json(&self.client, "/api/credentials", signed_request)?;
 		Ok(CredentialResponse {
 			success: response.success.unwrap_or(false),
 			error: response.error,
@@ -156,24 +411,100 @@ impl ManagerAPI {
 	}
 
 	pub fn get_tag(&self) -> Result<Option<String>, Error> {
-		#[derive(Serialize)]
-		struct Request<'a> {
-			username: &'a str,
-		}
 		#[derive(Deserialize)]
 		struct Response {
 			current: Option<String>,
 			all: Vec<String>,
 		}
 		let credentials = self.signer.get_api_credentials();
+		// Usernames are hex so they don't need escaping
+		let (_, body) = self.send(&self.client, reqwest::Method::GET, &format!("/api/tag?username={}", &credentials.username), None)?;
+		let response: Response = parse_response(&body)?;
+		Ok(response.current)
+	}
+
+	/// Gets the manager-signed snapshot of attendees for our current tag
+	pub fn get_attendees(&self) -> Result<SignedData, Error> {
+		#[derive(Serialize)]
+		struct Request {}
+		#[derive(Deserialize)]
+		struct Response {
+			snapshot: Option<String>,
+			signature: Option<String>,
+			error: Option<String>,
+			details: Option<String>,
+		}
+		let signed_request = self.sign_request(&Request {});
+
+		let response: Response = self.post_json(&self.client, "/api/attendees", signed_request)?;
+		let signed = match (response.snapshot, response.signature) {
+			(Some(data), Some(signature)) => SignedData { data, signature },
+			_ => {
+				println!("Attendee snapshot error: {:?} ({:?})", response.error, response.details);
+				return Err(Error::Message("Manager did not return an attendee snapshot"));
+			}
+		};
+		if !self.verify_signed_data(&signed) {
+			return Err(Error::Message("Invalid signature on attendee snapshot"));
+		}
+		Ok(signed)
+	}
+
+	pub fn heartbeat(&self) -> Result<(), Error> {
+		#[derive(Serialize)]
+		struct Request {
+			uptime: u64,
+			client_version: &'static str,
+			reader_connected: bool,
+			current_tag: Option<String>,
+		}
+		#[derive(Deserialize)]
+		struct Response {
+			success: bool,
+			#[serde(default)]
+			revoked: bool,
+			release: Option<Release>,
+		}
 		let request = Request {
-			username: &credentials.username
+			uptime: self.started.elapsed().as_secs(),
+			client_version: env!("CARGO_PKG_VERSION"),
+			reader_connected: self.reader_connected.load(Ordering::SeqCst),
+			current_tag: self.current_tag.read().unwrap().clone(),
 		};
-		let response: Response = self.client.get(self.base_url.join("/api/tag").unwrap())
-			.query(&request)
-			.send()?
-			.json()?;
-		Ok(response.current)
+		let signed_request = self.sign_request(&request);
+
+		let response: Response = self.post_json(&self.client, "/api/heartbeat", signed_request)?;
+		if response.revoked {
+			return Err(Error::Revoked);
+		}
+		if !response.success {
+			return Err(Error::Message("Heartbeat rejected by manager"));
+		}
+		if response.release.is_some() {
+			*self.offered_release.lock().unwrap() = response.release;
+		}
+		Ok(())
+	}
+
+	pub fn take_offered_release(&self) -> Option<Release> {
+		self.offered_release.lock().unwrap().take()
+	}
+
+	/// Downloads a client binary. The manager doesn't sign these responses so it has to be checked against its release signature before use.
+	pub fn download_release(&self, version: &str) -> Result<Vec<u8>, Error> {
+		// Binaries are big enough that the normal timeout isn't enough over WiFi
+		let download_client = reqwest::Client::builder()
+			.use_rustls_tls()
+			.timeout(Some(time::Duration::from_secs(300)))
+			.build()
+			.expect("Failed to construct client");
+		let mut response = download_client.get(self.base_url.join(&format!("/api/releases/{}", version)).unwrap()).send()?;
+		if !response.status().is_success() {
+			return Err(Error::Message("Manager doesn't have that release"));
+		}
+		let mut binary = Vec::new();
+		response.copy_to(&mut binary)?;
+		Ok(binary)
 	}
 
 	pub fn update_tag(&self, notifier: &Notifier) {
@@ -199,33 +530,381 @@ impl ManagerAPI {
 		}
 	}
 
+	/// Waits for the manager to push a change. Returns early with the current state if `version` is outdated.
+	pub fn wait_for_update(&self, version: Option<&str>) -> Result<Update, Error> {
+		#[derive(Serialize)]
+		struct Request<'a> {
+			version: Option<&'a str>,
+		}
+		#[derive(Deserialize)]
+		struct Response {
+			version: Option<String>,
+			current: Option<String>,
+			settings: Option<DeviceSettings>,
+			#[serde(default)]
+			commands: Vec<SignedData>,
+			#[serde(default)]
+			revoked: bool,
+			error: Option<String>,
+		}
+		let signed_request = self.sign_request(&Request { version });
+
+		let response: Response = self.post_json(&self.long_poll_client, "/api/updates", signed_request)?;
+		if response.revoked {
+			return Err(Error::Revoked);
+		}
+		match response.version {
+			Some(version) => Ok(Update {
+				version,
+				tag: response.current,
+				settings: response.settings,
+				commands: response.commands,
+			}),
+			None => {
+				println!("Update error: {:?}", response.error);
+				Err(Error::Message("Manager did not return an update"))
+			}
+		}
+	}
+
+	/// Replaces our key after an admin asks for it. The request is signed with the old key and includes a signature from the new one so the manager knows we hold both.
+	/// The key file is only replaced once the manager has switched over, after which we need to restart.
+	pub fn rotate_key(&self, command_id: &str) -> Result<(), Error> {
+		#[derive(Serialize)]
+		struct Request<'a> {
+			command_id: &'a str,
+			new_public_key: &'a str,
+			new_signature: String,
+			password: &'a str,
+		}
+		#[derive(Deserialize)]
+		struct Response {
+			success: bool,
+			error: Option<String>,
+			details: Option<String>,
+		}
+		let new_signer = self.signer.generate_replacement().map_err(|err| {
+			eprintln!("Could not create a new key: {}", err);
+			Error::Message("Could not create a new key")
+		})?;
+		let old_public_key = hex::encode(&self.signer.get_public_key()[..]);
+		let new_public_key = hex::encode(&new_signer.get_public_key()[..]);
+		// Must match `rotation_message` in the server
+		let message = format!("checkin-embedded rotate key {} {}", &old_public_key, &new_public_key);
+		let new_signature = hex::encode(&new_signer.sign(message.as_bytes()).to_bytes()[..]);
+		let credentials = new_signer.get_api_credentials();
+		let signed_request = self.sign_request(&Request {
+			command_id,
+			new_public_key: &new_public_key,
+			new_signature,
+			password: &credentials.password,
+		});
+
+		// If this fails without an answer the manager might have switched already, so the new key is kept for startup to sort out
+		let response: Response = self.post_json(&self.client, "/api/rotate-key", signed_request)?;
+		if !response.success {
+			println!("Key rotation error: {:?} ({:?})", response.error, response.details);
+			let _ = self.signer.discard_replacement();
+			return Err(Error::Message("Manager rejected key rotation"));
+		}
+		if let Err(err) = self.signer.replace_with(new_signer) {
+			// The manager only knows the new key now. It's still in the replacement file, which is tried again at startup.
+			eprintln!("Could not switch to the new key: {:?}", err);
+		}
+		Ok(())
+	}
+
+	/// Tells the manager that a command was run so that it shows up in the manager UI
+	pub fn acknowledge_command(&self, id: &str, success: bool, details: Option<String>) -> Result<(), Error> {
+		#[derive(Serialize)]
+		struct Request<'a> {
+			id: &'a str,
+			success: bool,
+			details: Option<String>,
+		}
+		#[derive(Deserialize)]
+		struct Response {
+			success: bool,
+		}
+		let signed_request = self.sign_request(&Request { id, success, details });
+
+		let response: Response = self.post_json(&self.client, "/api/commands/acknowledge", signed_request)?;
+		if !response.success {
+			return Err(Error::Message("Acknowledgement rejected by manager"));
+		}
+		Ok(())
+	}
+
+	/// Checks the signature and recipient of a command sent through the long-poll
+	fn verify_command(&self, signed: &SignedData) -> Option<Command> {
+		if !self.verify_signed_data(signed) {
+			println!("Ignoring command with an invalid signature");
+			return None;
+		}
+		let command: Command = match serde_json::from_str(&signed.data) {
+			Ok(command) => command,
+			Err(err) => {
+				println!("Ignoring malformed command: {:?}", err);
+				return None;
+			}
+		};
+		if command.device != self.get_name() {
+			println!("Ignoring command meant for {}", command.device);
+			return None;
+		}
+		if command.is_expired(Utc::now()) {
+			println!("Ignoring command issued at {}", command.issued_at.to_rfc3339());
+			return None;
+		}
+		Some(command)
+	}
+
+	/// Runs a command and acknowledges it. Returns whether the command is finished with.
+	fn run_command(&self, command: &Command, notifier: &Notifier) -> bool {
+		println!("Running {:?} from manager", command.command);
+		match command.command {
+			CommandKind::Identify => {
+				feedback::identify(notifier, command.message.as_ref().map(String::as_str).unwrap_or(""));
+			},
+			CommandKind::ShowMessage => {
+				notifier.scroll_text(command.message.as_ref().map(String::as_str).unwrap_or(""));
+			},
+			// The manager has already deleted our check-in account so starting over creates a new one
+			CommandKind::Reboot | CommandKind::RefetchCredentials => {
+				// Acknowledge first since we won't get another chance. If that fails the manager will send it again.
+				if let Err(err) = self.acknowledge_command(&command.id, true, None) {
+					println!("Command acknowledgement: {:?}", err);
+					return false;
+				}
+				let err = peripherals::restart();
+				println!("Restart failed: {:?}", err);
+				return true;
+			},
+			// The manager acknowledges this one for us since we come back with a different identity
+			CommandKind::RotateKey => {
+				match self.rotate_key(&command.id) {
+					Ok(()) => {
+						println!("Rotated key, restarting");
+						let err = peripherals::restart();
+						println!("Restart failed: {:?}", err);
+					},
+					Err(err) => {
+						println!("Key rotation: {:?}", err);
+						if self.signer.pending_replacement().is_some() {
+							// Didn't hear back so we don't know which key the manager has now. Startup finds out.
+							let err = peripherals::restart();
+							println!("Restart failed: {:?}", err);
+						}
+						else if let Err(err) = self.acknowledge_command(&command.id, false, Some(format!("{:?}", err))) {
+							println!("Command acknowledgement: {:?}", err);
+						}
+					},
+				}
+				return true;
+			},
+		}
+		if let Err(err) = self.acknowledge_command(&command.id, true, None) {
+			println!("Command acknowledgement: {:?}", err);
+		}
+		true
+	}
+
+	pub fn is_revoked(&self) -> bool {
+		self.revoked.load(Ordering::SeqCst)
+	}
+
+	// Either thread can hear about it first
+	fn revoke(&self, notifier: &Notifier) {
+		if !self.revoked.swap(true, Ordering::SeqCst) {
+			eprintln!("Check-in instance <{}> has been revoked in the manager UI", self.get_name());
+			feedback::revoked(notifier);
+			// Keeps reminding anyone nearby
+			notifier.set_idle_message(Some("Revoked".to_owned()));
+		}
+	}
+
+	// Shared by the polling and push threads
+	fn apply_tag(&self, new_tag: Option<String>, notifier: &Notifier) {
+		let mut tag = self.current_tag.write().unwrap();
+		match new_tag {
+			Some(new_tag) => {
+				// Only update if changed
+				if tag.is_none() || tag.as_ref().unwrap() != &new_tag {
+					*tag = Some(new_tag);
+					notifier.scroll_text(&format!("Using tag: {}", tag.as_ref().unwrap()));
+				}
+			},
+			None => {
+				// Only update if newly null
+				if tag.is_some() {
+					*tag = None;
+					notifier.scroll_text_speed("No tag defined by manager", 15);
+				}
+			},
+		}
+	}
+
+	fn apply_settings(&self, settings: DeviceSettings, notifier: &Notifier) {
+		let mut current = self.settings.lock().unwrap();
+		if current.as_ref() == Some(&settings) {
+			return;
+		}
+		println!("Applying settings from manager: {:?}", &settings);
+		notifier.set_brightness(settings.brightness);
+		notifier.set_scroll_speed(settings.scroll_speed);
+		notifier.set_buzzer_enabled(settings.buzzer_enabled);
+		notifier.set_tone_theme(settings.tone_theme);
+		notifier.set_idle_message(settings.idle_message.clone());
+		*current = Some(settings);
+	}
+
+	fn poll_interval(&self) -> time::Duration {
+		let configured = self.poll_interval.load(Ordering::SeqCst);
+		time::Duration::from_secs(effective_poll_interval(self.settings.lock().unwrap().as_ref(), configured))
+	}
+
 	pub fn start_polling_for_tag(&self, seconds: u64, notifier: Arc<Notifier>) {
+		self.poll_interval.store(seconds, Ordering::SeqCst);
 		// Spawn a thread that checks for current check-in tag
 		let thread_instance = self.clone();
-		let current_tag = Arc::clone(&self.current_tag);
 		thread::spawn(move || {
 			loop {
 				match ManagerAPI::get_tag(&thread_instance) {
-					Ok(Some(new_tag)) => {
-						let mut tag = current_tag.write().unwrap();
-						// Only update if changed
-						if tag.is_none() || tag.as_ref().unwrap() != &new_tag {
-							*tag = Some(new_tag);
-							notifier.scroll_text(&format!("Using tag: {}", tag.as_ref().unwrap()));
+					Ok(new_tag) => thread_instance.apply_tag(new_tag, &notifier),
+					Err(err) => println!("Tag check thread: {:?}", err)
+				}
+				// Let the manager know we're still alive
+				match thread_instance.heartbeat() {
+					Ok(()) => {},
+					Err(Error::Revoked) => thread_instance.revoke(&notifier),
+					Err(err) => println!("Heartbeat: {:?}", err),
+				}
+				thread::sleep(thread_instance.poll_interval());
+			}
+		});
+	}
+
+	/// Long-polls the manager so that tag and settings changes and commands show up immediately. Polling keeps running as a fallback.
+	pub fn start_listening_for_updates(&self, notifier: Arc<Notifier>) {
+		let thread_instance = self.clone();
+		thread::spawn(move || {
+			let mut version: Option<String> = None;
+			// Commands are sent again until the manager gets our acknowledgement so don't run them twice
+			let mut finished_commands: HashSet<String> = HashSet::new();
+			loop {
+				match thread_instance.wait_for_update(version.as_ref().map(String::as_str)) {
+					Ok(update) => {
+						if thread_instance.is_revoked() {
+							// Authorized again. Starting over sets up a new check-in account since the old one was deleted.
+							println!("Access restored in manager UI, restarting");
+							let err = peripherals::restart();
+							println!("Restart failed: {:?}", err);
 						}
-					},
-					Ok(None) => {
-						let mut tag = current_tag.write().unwrap();
-						// Only update if newly null
-						if tag.is_some() {
-							*tag = None;
-							notifier.scroll_text_speed("No tag defined by manager", 15);
+						version = Some(update.version);
+						thread_instance.apply_tag(update.tag, &notifier);
+						if let Some(settings) = update.settings {
+							thread_instance.apply_settings(settings, &notifier);
+						}
+						let mut sent_commands = HashSet::new();
+						for signed in update.commands.iter() {
+							let command = match thread_instance.verify_command(signed) {
+								Some(command) => command,
+								None => continue,
+							};
+							sent_commands.insert(command.id.clone());
+							if finished_commands.contains(&command.id) {
+								if let Err(err) = thread_instance.acknowledge_command(&command.id, true, None) {
+									println!("Command acknowledgement: {:?}", err);
+								}
+							}
+							else if thread_instance.run_command(&command, &notifier) {
+								finished_commands.insert(command.id);
+							}
 						}
+						// The manager stops sending a command once it has our acknowledgement or the command expires
+						finished_commands.retain(|id| sent_commands.contains(id));
 					},
-					Err(err) => println!("Tag check thread: {:?}", err)
+					Err(Error::Revoked) => {
+						thread_instance.revoke(&notifier);
+						// Keep checking in case we're authorized again
+						thread::sleep(time::Duration::from_secs(30));
+					},
+					Err(err) => {
+						println!("Update thread: {:?}", err);
+						// Back off so we don't hammer a manager that's down
+						thread::sleep(time::Duration::from_secs(10));
+					}
 				}
-				thread::sleep(time::Duration::from_secs(seconds));
 			}
 		});
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use rand::rngs::OsRng;
+	use ed25519_dalek::Keypair;
+	use super::*;
+
+	fn sent_request(challenge: &str, path: &str) -> SentRequest {
+		SentRequest {
+			challenge: challenge.to_owned(),
+			method: "GET".to_owned(),
+			path: path.to_owned(),
+			body_hash: crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, b""),
+		}
+	}
+
+	fn signed_response(keypair: &Keypair, challenge: &str, body: &[u8]) -> ChallengedResponse {
+		let request = sent_request(challenge, "/api/tag?username=a");
+		let signature = keypair.sign(&response_message(&request, 200, body));
+		ChallengedResponse {
+			request,
+			status: reqwest::StatusCode::OK,
+			body: body.to_vec(),
+			signature: Some(signature),
+		}
+	}
+
+	#[test]
+	fn poll_interval_from_config_is_kept_unless_the_manager_sets_one() {
+		let settings = r#"{"brightness": 15, "scroll_speed": 5, "buzzer_enabled": true, "tone_theme": "Classic", "idle_message": null}"#;
+		let settings: DeviceSettings = serde_json::from_str(settings).unwrap();
+		assert_eq!(settings.poll_interval, None);
+		assert_eq!(effective_poll_interval(None, 10), 10);
+		assert_eq!(effective_poll_interval(Some(&settings), 10), 10);
+
+		let settings = DeviceSettings { poll_interval: Some(20), ..settings };
+		assert_eq!(effective_poll_interval(Some(&settings), 10), 20);
+	}
+
+	#[test]
+	fn commands_expire() {
+		let command = r#"{"id": "1", "device": "abc", "command": "Reboot", "message": null, "issued_at": "2019-10-25T12:00:00Z"}"#;
+		let command: Command = serde_json::from_str(command).unwrap();
+		assert!(!command.is_expired(command.issued_at + chrono::Duration::minutes(9)));
+		assert!(command.is_expired(command.issued_at + chrono::Duration::minutes(10)));
+	}
+
+	#[test]
+	fn verifies_response_signatures() {
+		let mut csprng = OsRng::new().unwrap();
+		let keypair = Keypair::generate(&mut csprng);
+		let body = br#"{ "current": "lunch" }"#;
+		let response = signed_response(&keypair, "challenge", body);
+		assert_eq!(response.verify(&keypair.public).map(|(_, body)| body).ok(), Some(body.to_vec()));
+
+		let other = Keypair::generate(&mut csprng);
+		assert!(signed_response(&keypair, "challenge", body).verify(&other.public).is_err());
+		let unsigned = ChallengedResponse { signature: None, ..signed_response(&keypair, "challenge", body) };
+		assert!(unsigned.verify(&keypair.public).is_err());
+		let tampered = ChallengedResponse { body: br#"{ "current": "dinner" }"#.to_vec(), ..signed_response(&keypair, "challenge", body) };
+		assert!(tampered.verify(&keypair.public).is_err());
+		// Replayed in response to a different request
+		let replayed = ChallengedResponse { request: sent_request("another challenge", "/api/tag?username=a"), ..signed_response(&keypair, "challenge", body) };
+		assert!(replayed.verify(&keypair.public).is_err());
+		// Another device's tag passed off as ours
+		let redirected = ChallengedResponse { request: sent_request("challenge", "/api/tag?username=b"), ..signed_response(&keypair, "challenge", body) };
+		assert!(redirected.verify(&keypair.public).is_err());
+	}
+}
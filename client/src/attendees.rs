@@ -0,0 +1,245 @@
+use std::fs;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::sync::{ Arc, RwLock };
+use std::{ thread, time };
+use serde::Deserialize;
+use chrono::{ DateTime, Utc };
+use ed25519_dalek::PublicKey;
+use crate::api::{ ManagerAPI, SignedData };
+
+#[derive(Deserialize)]
+struct Attendee {
+	id: String,
+	checked_in: bool,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+	tag: String,
+	generated: String,
+	attendees: Vec<Attendee>,
+}
+
+/// What the local cache thinks of a badge tap when the check-in API can't be reached
+#[derive(Debug, PartialEq)]
+pub enum LocalDecision {
+	Success,
+	AlreadyCheckedIn,
+	Invalid,
+	// No snapshot for the current tag so we can't say either way
+	Unknown,
+}
+
+struct Cache {
+	tag: String,
+	generated: DateTime<Utc>,
+	checked_in: HashMap<String, bool>,
+}
+
+/// Manager-signed list of attendees for the current tag, kept on disk so badges can be validated while offline
+pub struct AttendeeCache {
+	path: PathBuf,
+	cache: RwLock<Option<Cache>>,
+	// Snapshots older than this could let in badges checked in since, so they aren't used
+	max_age: chrono::Duration,
+	// Only the key from the config is trusted to let people in while offline, not one pinned from whoever answered first
+	manager_key: Option<PublicKey>,
+}
+
+impl AttendeeCache {
+	/// Starts without a snapshot, so every tap is `Unknown` until one is downloaded. Snapshots are never used without `manager_key`.
+	pub fn new(path: &str, max_age_minutes: u64, manager_key: Option<PublicKey>) -> Self {
+		AttendeeCache {
+			path: PathBuf::from(path),
+			cache: RwLock::new(None),
+			max_age: chrono::Duration::minutes(max_age_minutes as i64),
+			manager_key,
+		}
+	}
+
+	fn verify(&self, signed: &SignedData) -> bool {
+		self.manager_key.as_ref().map(|key| signed.verify(key)).unwrap_or(false)
+	}
+
+	pub fn load(path: &str, max_age_minutes: u64, manager_key: Option<PublicKey>) -> Self {
+		let cache = AttendeeCache::new(path, max_age_minutes, manager_key);
+		if cache.manager_key.is_none() {
+			println!("Badges can't be checked while offline without manager_key in the config");
+			return cache;
+		}
+		let signed: Option<SignedData> = fs::read_to_string(&cache.path).ok().and_then(|file| serde_json::from_str(&file).ok());
+		if let Some(signed) = signed {
+			// Signature is checked again so that a tampered file on the SD card can't be used to let people in
+			if cache.verify(&signed) {
+				cache.replace(&signed);
+			}
+			else {
+				eprintln!("Ignoring attendee cache with invalid signature");
+			}
+		}
+		cache
+	}
+
+	fn replace(&self, signed: &SignedData) {
+		let snapshot: Snapshot = match serde_json::from_str(&signed.data) {
+			Ok(snapshot) => snapshot,
+			Err(err) => {
+				eprintln!("Invalid attendee snapshot: {:?}", err);
+				return;
+			}
+		};
+		let generated = match DateTime::parse_from_rfc3339(&snapshot.generated) {
+			Ok(generated) => generated.with_timezone(&Utc),
+			Err(err) => {
+				eprintln!("Invalid attendee snapshot time: {:?}", err);
+				return;
+			}
+		};
+		let checked_in = snapshot.attendees.into_iter().map(|attendee| (attendee.id, attendee.checked_in)).collect();
+		*self.cache.write().unwrap() = Some(Cache {
+			tag: snapshot.tag,
+			generated,
+			checked_in,
+		});
+	}
+
+	fn refresh(&self, manager: &ManagerAPI) {
+		let signed = match manager.get_attendees() {
+			Ok(signed) => signed,
+			Err(err) => {
+				println!("Refreshing attendee cache: {:?}", err);
+				return;
+			}
+		};
+		if !self.verify(&signed) {
+			eprintln!("Attendee snapshot isn't signed by the configured manager key");
+			return;
+		}
+		match serde_json::to_string(&signed) {
+			Ok(file) => {
+				if let Err(err) = fs::write(&self.path, file) {
+					eprintln!("Error writing attendee cache: {:?}", err);
+				}
+			},
+			Err(err) => eprintln!("Could not serialize attendee cache: {:?}", err),
+		}
+		self.replace(&signed);
+	}
+
+	/// Spawns a thread that periodically downloads a fresh snapshot for the device's current tag
+	pub fn start_refreshing(cache: &Arc<AttendeeCache>, manager: &Arc<ManagerAPI>, interval: u64) {
+		if cache.manager_key.is_none() {
+			return;
+		}
+		let cache = Arc::clone(cache);
+		let manager = Arc::clone(manager);
+		thread::spawn(move || {
+			loop {
+				cache.refresh(&manager);
+				thread::sleep(time::Duration::from_secs(interval));
+			}
+		});
+	}
+
+	/// Decides a badge tap locally and records successes so that a second tap while offline is caught
+	pub fn decide(&self, badge_id: &str, tag: &str) -> LocalDecision {
+		let mut cache = self.cache.write().unwrap();
+		let cache = match cache.as_mut() {
+			Some(cache) if cache.tag == tag => cache,
+			_ => return LocalDecision::Unknown,
+		};
+		if Utc::now().signed_duration_since(cache.generated) > self.max_age {
+			println!("Attendee snapshot from {} is too old to check badge {}", cache.generated.to_rfc3339(), badge_id);
+			return LocalDecision::Unknown;
+		}
+		match cache.checked_in.get_mut(badge_id) {
+			Some(true) => LocalDecision::AlreadyCheckedIn,
+			Some(checked_in) => {
+				*checked_in = true;
+				LocalDecision::Success
+			},
+			None => {
+				println!("Badge {} not in attendee snapshot from {}", badge_id, cache.generated.to_rfc3339());
+				LocalDecision::Invalid
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn snapshot(generated: DateTime<Utc>) -> String {
+		serde_json::json!({
+			"tag": "lunch",
+			"generated": generated.to_rfc3339(),
+			"attendees": [
+				{ "id": "arrived", "checked_in": true },
+				{ "id": "hungry", "checked_in": false },
+			],
+		}).to_string()
+	}
+
+	fn cache_with_snapshot(generated: DateTime<Utc>) -> AttendeeCache {
+		let cache = AttendeeCache::new("./attendees.json", 60, None);
+		cache.replace(&SignedData {
+			data: snapshot(generated),
+			signature: String::new(),
+		});
+		cache
+	}
+
+	#[test]
+	fn decides_taps_from_the_snapshot() {
+		let cache = cache_with_snapshot(Utc::now());
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::AlreadyCheckedIn);
+		assert_eq!(cache.decide("stranger", "lunch"), LocalDecision::Invalid);
+		assert_eq!(cache.decide("hungry", "dinner"), LocalDecision::Unknown);
+		assert_eq!(cache.decide("hungry", "lunch"), LocalDecision::Success);
+		// Caught even though the first tap is still queued
+		assert_eq!(cache.decide("hungry", "lunch"), LocalDecision::AlreadyCheckedIn);
+	}
+
+	#[test]
+	fn knows_nothing_without_a_snapshot() {
+		let cache = AttendeeCache::new("./attendees.json", 60, None);
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::Unknown);
+	}
+
+	#[test]
+	fn ignores_old_snapshots() {
+		let cache = cache_with_snapshot(Utc::now() - chrono::Duration::minutes(61));
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::Unknown);
+		assert_eq!(cache.decide("stranger", "lunch"), LocalDecision::Unknown);
+		assert_eq!(cache.decide("hungry", "lunch"), LocalDecision::Unknown);
+	}
+
+	#[test]
+	fn only_trusts_the_configured_manager_key() {
+		use ed25519_dalek::Keypair;
+		use rand::rngs::OsRng;
+
+		let mut csprng = OsRng::new().unwrap();
+		let manager = Keypair::generate(&mut csprng);
+		let data = snapshot(Utc::now());
+		let signed = SignedData {
+			signature: hex::encode(&manager.sign(data.as_bytes()).to_bytes()[..]),
+			data,
+		};
+		let path = std::env::temp_dir().join(format!("checkin-attendees-{}", std::process::id()));
+		let path = path.to_str().unwrap();
+		fs::write(path, serde_json::to_string(&signed).unwrap()).unwrap();
+
+		let cache = AttendeeCache::load(path, 60, Some(manager.public));
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::AlreadyCheckedIn);
+		let other = Keypair::generate(&mut csprng);
+		let cache = AttendeeCache::load(path, 60, Some(other.public));
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::Unknown);
+		// A key pinned from the first manager we reached isn't enough
+		let cache = AttendeeCache::load(path, 60, None);
+		assert_eq!(cache.decide("arrived", "lunch"), LocalDecision::Unknown);
+		fs::remove_file(path).unwrap();
+	}
+}
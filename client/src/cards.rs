@@ -0,0 +1,238 @@
+use std::fs;
+use std::io::{ self, BufRead };
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::{ Mutex, mpsc };
+use std::{ thread, time };
+use hackgt_nfc::nfc::{ handle_cards, NFCBadge };
+
+/// Something that happened at a card reader
+#[derive(Debug, PartialEq)]
+pub enum CardEvent {
+	/// A badge was tapped and this user ID was read from it
+	Tap(String),
+	/// A badge was tapped but couldn't be read
+	ReadError(String),
+	/// A reader was plugged in (true) or removed (false)
+	ReaderChanged(bool),
+}
+
+/// Where badge taps come from. Several sources can feed the same channel.
+pub trait CardSource {
+	/// Spawns whatever threads are needed and returns immediately. The source stops once `events` is disconnected.
+	fn start(self: Box<Self>, events: mpsc::Sender<CardEvent>);
+}
+
+/// Parses one event in the format used by replay files and the socket injector:
+///     tap <user ID>
+///     error <message>
+///     reader connected | reader disconnected
+/// A line with just a user ID is also treated as a tap.
+pub fn parse_event(line: &str) -> Option<CardEvent> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return None;
+	}
+	let mut parts = line.splitn(2, char::is_whitespace);
+	let command = parts.next().unwrap();
+	let argument = parts.next().map(str::trim).unwrap_or("");
+	match (command, argument) {
+		("tap", id) if !id.is_empty() => Some(CardEvent::Tap(id.to_owned())),
+		("error", message) => Some(CardEvent::ReadError(message.to_owned())),
+		("reader", "connected") => Some(CardEvent::ReaderChanged(true)),
+		("reader", "disconnected") => Some(CardEvent::ReaderChanged(false)),
+		(id, "") => Some(CardEvent::Tap(id.to_owned())),
+		_ => {
+			eprintln!("Ignoring unknown card event: {}", line);
+			None
+		}
+	}
+}
+
+/// A physical NFC reader through PC/SC (pcsc-lite on Linux)
+pub struct PCSCSource;
+
+impl CardSource for PCSCSource {
+	fn start(self: Box<Self>, events: mpsc::Sender<CardEvent>) {
+		let reader_events = Mutex::new(events.clone());
+		let events = Mutex::new(events);
+		handle_cards(move |card, _reader, _reader_index| {
+			let badge = NFCBadge::new(&card);
+			badge.set_buzzer(false).unwrap();
+
+			// THIS IS SLOWWWWW
+			// My 3:40am guess is that the notifier is causing some kind of hold on the &card argument
+			// fake news
+			// my 3:42am knowledges says that this is somehow the cause:
+			// badge.get_user_id().unwrap();
+			// Only seems to be a problem on Linux (pcsclite)
+			// I ran the same code on Windows and it was significantly faster
+
+			let event = match badge.get_user_id() {
+				Ok(id) => CardEvent::Tap(id),
+				Err(err) => CardEvent::ReadError(format!("{:?}", err)),
+			};
+			let _ = events.lock().unwrap().send(event);
+		}, move |_reader, added| {
+			let _ = reader_events.lock().unwrap().send(CardEvent::ReaderChanged(added));
+		});
+	}
+}
+
+/// Plays back a file of taps with timings. Each line is the number of milliseconds to wait followed by an event:
+///     1500 tap 5c8a1f2e3d4b
+///     200 reader disconnected
+pub struct ReplaySource {
+	path: PathBuf,
+}
+
+impl ReplaySource {
+	pub fn new(path: &str) -> Self {
+		Self { path: PathBuf::from(path) }
+	}
+
+	fn parse(script: &str) -> Vec<(time::Duration, CardEvent)> {
+		script.lines().filter_map(|line| {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				return None;
+			}
+			let mut parts = line.splitn(2, char::is_whitespace);
+			let delay = match parts.next().unwrap().parse::<u64>() {
+				Ok(delay) => delay,
+				Err(_) => {
+					eprintln!("Replay line is missing a delay: {}", line);
+					return None;
+				}
+			};
+			let event = parse_event(parts.next().unwrap_or(""))?;
+			Some((time::Duration::from_millis(delay), event))
+		}).collect()
+	}
+}
+
+impl CardSource for ReplaySource {
+	fn start(self: Box<Self>, events: mpsc::Sender<CardEvent>) {
+		let script = fs::read_to_string(&self.path).expect("Could not read replay file");
+		let script = ReplaySource::parse(&script);
+		println!("Replaying {} card events from {}", script.len(), self.path.display());
+		thread::spawn(move || {
+			// Pretend a reader is plugged in like it would be on a real device
+			let _ = events.send(CardEvent::ReaderChanged(true));
+			for (delay, event) in script {
+				thread::sleep(delay);
+				if events.send(event).is_err() {
+					break;
+				}
+			}
+		});
+	}
+}
+
+/// Accepts events from other processes over a UNIX socket, one per line (see `parse_event`)
+pub struct SocketSource {
+	path: PathBuf,
+}
+
+impl SocketSource {
+	pub fn new(path: &str) -> Self {
+		Self { path: PathBuf::from(path) }
+	}
+}
+
+/// Sends each line that parses as an event until the reader ends or nobody is listening
+pub fn forward_events<R: BufRead>(reader: R, events: mpsc::Sender<CardEvent>) {
+	for line in reader.lines() {
+		let line = match line {
+			Ok(line) => line,
+			Err(_) => break,
+		};
+		if let Some(event) = parse_event(&line) {
+			if events.send(event).is_err() {
+				break;
+			}
+		}
+	}
+}
+
+impl CardSource for SocketSource {
+	fn start(self: Box<Self>, events: mpsc::Sender<CardEvent>) {
+		// Left over from a previous run
+		let _ = fs::remove_file(&self.path);
+		let listener = match UnixListener::bind(&self.path) {
+			Ok(listener) => listener,
+			Err(err) => {
+				eprintln!("Could not open card socket at {}: {:?}", self.path.display(), err);
+				return;
+			}
+		};
+		println!("Taps can be sent with: echo <user ID> | nc -U {}", self.path.display());
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let stream = match stream {
+					Ok(stream) => stream,
+					Err(err) => {
+						eprintln!("Card socket error: {:?}", err);
+						thread::sleep(time::Duration::from_millis(100));
+						continue;
+					}
+				};
+				let events = events.clone();
+				thread::spawn(move || forward_events(io::BufReader::new(stream), events));
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+	use std::os::unix::net::UnixStream;
+	use super::*;
+
+	#[test]
+	fn parses_events() {
+		assert_eq!(parse_event("tap abc123"), Some(CardEvent::Tap("abc123".to_owned())));
+		assert_eq!(parse_event("  abc123  "), Some(CardEvent::Tap("abc123".to_owned())));
+		assert_eq!(parse_event("error Card removed"), Some(CardEvent::ReadError("Card removed".to_owned())));
+		assert_eq!(parse_event("reader disconnected"), Some(CardEvent::ReaderChanged(false)));
+		assert_eq!(parse_event("# comment"), None);
+		assert_eq!(parse_event(""), None);
+		assert_eq!(parse_event("reader sideways"), None);
+	}
+
+	#[test]
+	fn replays_script_in_order() {
+		let path = std::env::temp_dir().join(format!("checkin-replay-{}.txt", std::process::id()));
+		fs::write(&path, "# start\n10 tap first\nnot a delay\n0 error Card removed\n5 reader disconnected\n").unwrap();
+
+		let (sender, receiver) = mpsc::channel();
+		Box::new(ReplaySource::new(path.to_str().unwrap())).start(sender);
+		let events: Vec<CardEvent> = receiver.iter().collect();
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(events, vec![
+			CardEvent::ReaderChanged(true),
+			CardEvent::Tap("first".to_owned()),
+			CardEvent::ReadError("Card removed".to_owned()),
+			CardEvent::ReaderChanged(false),
+		]);
+	}
+
+	#[test]
+	fn injects_taps_over_socket() {
+		let path = std::env::temp_dir().join(format!("checkin-cards-{}.sock", std::process::id()));
+		let (sender, receiver) = mpsc::channel();
+		Box::new(SocketSource::new(path.to_str().unwrap())).start(sender);
+
+		let mut stream = UnixStream::connect(&path).unwrap();
+		writeln!(stream, "tap abc123").unwrap();
+		writeln!(stream, "reader connected").unwrap();
+		drop(stream);
+
+		let timeout = time::Duration::from_secs(5);
+		assert_eq!(receiver.recv_timeout(timeout).unwrap(), CardEvent::Tap("abc123".to_owned()));
+		assert_eq!(receiver.recv_timeout(timeout).unwrap(), CardEvent::ReaderChanged(true));
+		let _ = fs::remove_file(&path);
+	}
+}
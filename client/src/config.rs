@@ -0,0 +1,259 @@
+use std::{ env, fs, io };
+use std::collections::HashSet;
+use std::str::FromStr;
+use serde::Deserialize;
+use url::Url;
+use ed25519_dalek::PublicKey;
+
+const DEFAULT_PATH: &'static str = "./checkin.toml";
+
+/// Settings for a kiosk. Read from `./checkin.toml` (or the file named by `CHECKIN_CONFIG`) and then
+/// overridden by `CHECKIN_<FIELD>` environment variables, e.g. `CHECKIN_MANAGER_URL` or `CHECKIN_BUZZER_PIN`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub manager_url: String,
+	/// Hex ed25519 public key that the manager signs responses with. Pinned the first time we reach the manager if not set.
+	pub manager_key: Option<String>,
+	/// I2C address of the HT16K33 behind the success (left) display
+	pub success_display_address: u8,
+	/// I2C address of the HT16K33 behind the error (right) display
+	pub error_display_address: u8,
+	/// BCM GPIO pin numbers
+	pub buzzer_pin: u8,
+	pub tag_button_pin: u8,
+	pub reset_button_pin: u8,
+	/// Seconds between polling the manager for the current tag
+	pub poll_interval: u64,
+	pub key_file: String,
+	/// Encrypts the key file. Can be combined with `bind_key_to_machine`.
+	pub key_passphrase: Option<String>,
+	/// Encrypts the key file with this Pi's serial number so that it's useless on another device. Only works on a Raspberry Pi.
+	pub bind_key_to_machine: bool,
+	/// Hex ed25519 public key that client releases are signed with. Automatic updates are off without one.
+	pub release_key: Option<String>,
+	/// A new build that can't start up in this many minutes is replaced by the previous one
+	pub rollback_minutes: u64,
+	/// Attendee snapshots older than this aren't used to check badges while the check-in API is unreachable
+	pub snapshot_max_age_minutes: u64,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			manager_url: Config::default_manager_url().to_owned(),
+			manager_key: None,
+			success_display_address: 0x70,
+			error_display_address: 0x71,
+			buzzer_pin: 18,
+			tag_button_pin: 23,
+			reset_button_pin: 24,
+			poll_interval: 30,
+			key_file: "./instance.key".to_owned(),
+			key_passphrase: None,
+			bind_key_to_machine: false,
+			release_key: None,
+			rollback_minutes: 10,
+			snapshot_max_age_minutes: 60,
+		}
+	}
+}
+
+// I2C addresses above and below these are reserved
+const MIN_I2C_ADDRESS: u8 = 0x03;
+const MAX_I2C_ADDRESS: u8 = 0x77;
+// The Pi's header exposes BCM GPIO 0 to 27
+const MAX_GPIO_PIN: u8 = 27;
+
+fn parse_address(value: &str) -> Option<u8> {
+	let value = value.trim();
+	if value.starts_with("0x") || value.starts_with("0X") {
+		u8::from_str_radix(&value[2..], 16).ok()
+	}
+	else {
+		value.parse().ok()
+	}
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+	value.trim().parse().ok()
+}
+
+impl Config {
+	#[cfg(debug_assertions)]
+	fn default_manager_url() -> &'static str {
+		// Manager started locally with `cargo run`
+		"http://localhost:3000"
+	}
+	#[cfg(not(debug_assertions))]
+	fn default_manager_url() -> &'static str {
+		"https://manager.checkin.hack.gt"
+	}
+
+	/// Returns every problem found so that they can all be fixed at once
+	pub fn load() -> Result<Self, Vec<String>> {
+		let path = env::var("CHECKIN_CONFIG").unwrap_or(DEFAULT_PATH.to_owned());
+		let mut config = match fs::read_to_string(&path) {
+			Ok(file) => Config::from_toml(&file).map_err(|err| vec![format!("{}: {}", &path, err)])?,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Config::default(),
+			Err(err) => return Err(vec![format!("Could not read {}: {}", &path, err)]),
+		};
+		let mut errors = Vec::new();
+		config.apply_overrides(|name| env::var(name).ok(), &mut errors);
+		config.validate(&mut errors);
+		if errors.is_empty() {
+			Ok(config)
+		}
+		else {
+			Err(errors)
+		}
+	}
+
+	fn from_toml(file: &str) -> Result<Self, toml::de::Error> {
+		toml::from_str(file)
+	}
+
+	fn apply_overrides<F: Fn(&str) -> Option<String>>(&mut self, lookup: F, errors: &mut Vec<String>) {
+		fn apply<T, F: Fn(&str) -> Option<String>>(lookup: &F, name: &str, field: &mut T, parser: fn(&str) -> Option<T>, errors: &mut Vec<String>) {
+			if let Some(value) = lookup(name) {
+				match parser(&value) {
+					Some(value) => *field = value,
+					None => errors.push(format!("{} has an invalid value: {}", name, value)),
+				}
+			}
+		}
+		if let Some(url) = lookup("CHECKIN_MANAGER_URL") {
+			self.manager_url = url;
+		}
+		if let Some(manager_key) = lookup("CHECKIN_MANAGER_KEY") {
+			self.manager_key = Some(manager_key);
+		}
+		apply(&lookup, "CHECKIN_SUCCESS_DISPLAY_ADDRESS", &mut self.success_display_address, parse_address, errors);
+		apply(&lookup, "CHECKIN_ERROR_DISPLAY_ADDRESS", &mut self.error_display_address, parse_address, errors);
+		apply(&lookup, "CHECKIN_BUZZER_PIN", &mut self.buzzer_pin, parse, errors);
+		apply(&lookup, "CHECKIN_TAG_BUTTON_PIN", &mut self.tag_button_pin, parse, errors);
+		apply(&lookup, "CHECKIN_RESET_BUTTON_PIN", &mut self.reset_button_pin, parse, errors);
+		apply(&lookup, "CHECKIN_POLL_INTERVAL", &mut self.poll_interval, parse, errors);
+		if let Some(key_file) = lookup("CHECKIN_KEY_FILE") {
+			self.key_file = key_file;
+		}
+		if let Some(passphrase) = lookup("CHECKIN_KEY_PASSPHRASE") {
+			self.key_passphrase = Some(passphrase);
+		}
+		apply(&lookup, "CHECKIN_BIND_KEY_TO_MACHINE", &mut self.bind_key_to_machine, parse, errors);
+		if let Some(release_key) = lookup("CHECKIN_RELEASE_KEY") {
+			self.release_key = Some(release_key);
+		}
+		apply(&lookup, "CHECKIN_ROLLBACK_MINUTES", &mut self.rollback_minutes, parse, errors);
+		apply(&lookup, "CHECKIN_SNAPSHOT_MAX_AGE_MINUTES", &mut self.snapshot_max_age_minutes, parse, errors);
+	}
+
+	fn validate(&self, errors: &mut Vec<String>) {
+		match Url::parse(&self.manager_url) {
+			Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {},
+			_ => errors.push(format!("manager_url must be an http(s) URL: {}", &self.manager_url)),
+		}
+
+		for &(name, address) in &[("success_display_address", self.success_display_address), ("error_display_address", self.error_display_address)] {
+			if address < MIN_I2C_ADDRESS || address > MAX_I2C_ADDRESS {
+				errors.push(format!("{} must be an I2C address from {:#04x} to {:#04x}: {:#04x}", name, MIN_I2C_ADDRESS, MAX_I2C_ADDRESS, address));
+			}
+		}
+		if self.success_display_address == self.error_display_address {
+			errors.push(format!("success_display_address and error_display_address are both {:#04x}", self.success_display_address));
+		}
+
+		let pins = [("buzzer_pin", self.buzzer_pin), ("tag_button_pin", self.tag_button_pin), ("reset_button_pin", self.reset_button_pin)];
+		let mut used_pins = HashSet::new();
+		for &(name, pin) in pins.iter() {
+			if pin > MAX_GPIO_PIN {
+				errors.push(format!("{} must be a GPIO pin from 0 to {}: {}", name, MAX_GPIO_PIN, pin));
+			}
+			else if !used_pins.insert(pin) {
+				errors.push(format!("{} uses GPIO {} which is already taken", name, pin));
+			}
+		}
+
+		if self.poll_interval == 0 {
+			errors.push("poll_interval must be at least 1 second".to_owned());
+		}
+		if self.key_file.trim().is_empty() {
+			errors.push("key_file must not be empty".to_owned());
+		}
+		if self.key_passphrase.as_ref().map(String::is_empty).unwrap_or(false) {
+			errors.push("key_passphrase must not be empty".to_owned());
+		}
+		for &(name, key) in &[("manager_key", &self.manager_key), ("release_key", &self.release_key)] {
+			if let Some(key) = key {
+				let valid = hex::decode(key).ok().map(|key| PublicKey::from_bytes(&key).is_ok()).unwrap_or(false);
+				if !valid {
+					errors.push(format!("{} must be a hex ed25519 public key: {}", name, key));
+				}
+			}
+		}
+		if self.rollback_minutes == 0 {
+			errors.push("rollback_minutes must be at least 1 minute".to_owned());
+		}
+		if self.snapshot_max_age_minutes == 0 {
+			errors.push("snapshot_max_age_minutes must be at least 1 minute".to_owned());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use super::*;
+
+	fn errors_for(config: &Config) -> Vec<String> {
+		let mut errors = Vec::new();
+		config.validate(&mut errors);
+		errors
+	}
+
+	#[test]
+	fn defaults_are_valid() {
+		assert!(errors_for(&Config::default()).is_empty());
+	}
+
+	#[test]
+	fn reads_partial_toml() {
+		let config = Config::from_toml("manager_url = \"https://example.com\"\nsuccess_display_address = 0x72\n").unwrap();
+		assert_eq!(config.manager_url, "https://example.com");
+		assert_eq!(config.success_display_address, 0x72);
+		assert_eq!(config.error_display_address, 0x71);
+		assert!(Config::from_toml("buzzer = 18\n").is_err());
+	}
+
+	#[test]
+	fn env_overrides_file() {
+		let mut env = HashMap::new();
+		env.insert("CHECKIN_ERROR_DISPLAY_ADDRESS", "0x74");
+		env.insert("CHECKIN_POLL_INTERVAL", "10");
+		env.insert("CHECKIN_BUZZER_PIN", "loud");
+		env.insert("CHECKIN_BIND_KEY_TO_MACHINE", "true");
+		let mut config = Config::default();
+		let mut errors = Vec::new();
+		config.apply_overrides(|name| env.get(name).map(|value| value.to_string()), &mut errors);
+		assert_eq!(config.error_display_address, 0x74);
+		assert_eq!(config.poll_interval, 10);
+		assert_eq!(config.buzzer_pin, 18);
+		assert!(config.bind_key_to_machine);
+		assert_eq!(errors, vec!["CHECKIN_BUZZER_PIN has an invalid value: loud".to_owned()]);
+	}
+
+	#[test]
+	fn rejects_invalid_values() {
+		let mut config = Config::default();
+		config.manager_url = "192.168.1.15:3000".to_owned();
+		config.error_display_address = 0x70;
+		config.tag_button_pin = 18;
+		config.reset_button_pin = 40;
+		config.poll_interval = 0;
+		config.key_passphrase = Some(String::new());
+		config.release_key = Some("not a key".to_owned());
+		config.manager_key = Some("abcd".to_owned());
+		config.snapshot_max_age_minutes = 0;
+		assert_eq!(errors_for(&config).len(), 9);
+	}
+}
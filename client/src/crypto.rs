@@ -1,6 +1,8 @@
 use std::fs;
+use std::path::Path;
 use rand::rngs::OsRng;
 use ed25519_dalek::{ Keypair, Signature };
+use crate::keyfile;
 
 pub struct APICredentials {
 	pub username: String,
@@ -9,25 +11,66 @@ pub struct APICredentials {
 
 pub struct Signer {
 	keypair: Keypair,
+	path: String,
+	// Key file is encrypted with this when there is one (see keyfile::secret)
+	secret: Option<Vec<u8>>,
 }
 
 impl Signer {
-	pub fn load() -> Self {
-		const KEY_NAME: &'static str = "./instance.key";
-		let keypair = match fs::read(KEY_NAME) {
-			Ok(file) => {
-				Keypair::from_bytes(&file).expect("Invalid key")
+	/// Generates a key if `path` doesn't exist yet
+	pub fn load(path: &str, secret: Option<&[u8]>) -> Result<Self, keyfile::Error> {
+		let keypair = match keyfile::load(path, secret) {
+			Ok(key) => {
+				Keypair::from_bytes(&key).map_err(|_| keyfile::Error::Corrupt)?
 			},
-			Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+			Err(keyfile::Error::IO(ref err)) if err.kind() == std::io::ErrorKind::NotFound => {
 				let mut csprng = OsRng::new().unwrap();
 				let key = Keypair::generate(&mut csprng);
 
-				fs::write(KEY_NAME, &key.to_bytes()[..]).expect("Error writing key to file");
+				keyfile::save(path, &key.to_bytes(), secret)?;
 				key
 			},
-			Err(err) => panic!("There was an error opening the key file: {:?}", err),
+			Err(err) => return Err(err),
 		};
-		Self { keypair }
+		Ok(Self { keypair, path: path.to_owned(), secret: secret.map(<[u8]>::to_vec) })
+	}
+
+	fn replacement_path(&self) -> String {
+		format!("{}.new", &self.path)
+	}
+
+	/// Generates a key to replace this one. It's saved next to the current key until `replace_with` so that it isn't lost if the manager accepts it
+	/// and we crash or never hear back. `pending_replacement` picks it up at startup.
+	pub fn generate_replacement(&self) -> Result<Self, keyfile::Error> {
+		let path = self.replacement_path();
+		// Left over from a rotation that the manager turned down
+		let _ = fs::remove_file(&path);
+		Signer::load(&path, self.secret.as_ref().map(Vec::as_slice))
+	}
+
+	/// A replacement key left behind by a rotation that we don't know the outcome of
+	pub fn pending_replacement(&self) -> Option<Self> {
+		let path = self.replacement_path();
+		if !Path::new(&path).exists() {
+			return None;
+		}
+		match Signer::load(&path, self.secret.as_ref().map(Vec::as_slice)) {
+			Ok(replacement) => Some(replacement),
+			Err(err) => {
+				eprintln!("Ignoring unreadable replacement key {}: {}", &path, err);
+				None
+			},
+		}
+	}
+
+	pub fn discard_replacement(&self) -> std::io::Result<()> {
+		fs::remove_file(self.replacement_path())
+	}
+
+	/// Makes `replacement` our key on disk and returns it to use from now on
+	pub fn replace_with(&self, replacement: Signer) -> std::io::Result<Self> {
+		fs::rename(&replacement.path, &self.path)?;
+		Ok(Self { path: self.path.clone(), ..replacement })
 	}
 
 	pub fn sign(&self, message: &[u8]) -> Signature {
@@ -42,6 +85,16 @@ impl Signer {
 		self.keypair.public.to_bytes()
 	}
 
+	/// Six digit code scrolled while waiting for approval and typed into the manager UI to approve us.
+	/// Must match `Device::pairing_code` in the server.
+	pub fn get_pairing_code(&self) -> String {
+		let mut data = b"checkin-embedded pairing code ".to_vec();
+		data.extend_from_slice(&self.keypair.public.to_bytes());
+		let hash = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &data);
+		let code = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+		format!("{:03} {:03}", code / 1000, code % 1000)
+	}
+
 	// API credentials are comprised of
 	//     Username = SHA-256 of public key (so that names don't always start with the same characters)
 	//     Password = SHA-512 of private key
@@ -55,6 +108,51 @@ impl Signer {
 
 impl std::clone::Clone for Signer {
 	fn clone(&self) -> Self {
-		Signer::load()
+		// Decrypting the file again would mean another round of Argon2
+		Self {
+			keypair: Keypair::from_bytes(&self.keypair.to_bytes()).unwrap(),
+			path: self.path.clone(),
+			secret: self.secret.clone(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pairing_code_matches_the_manager() {
+		// Same key as `pairing_code_matches_devices` in the server's models.rs
+		let mut key = vec![7; 32];
+		key.extend(hex::decode("ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c").unwrap());
+		let signer = Signer {
+			keypair: Keypair::from_bytes(&key).unwrap(),
+			path: String::new(),
+			secret: None,
+		};
+		assert_eq!(signer.get_pairing_code(), "420 191");
+	}
+
+	#[test]
+	fn replacement_survives_until_replaced_or_discarded() {
+		let path = std::env::temp_dir().join(format!("checkin-signer-{}", std::process::id()));
+		let path = path.to_str().unwrap();
+		let signer = Signer::load(path, None).unwrap();
+		assert!(signer.pending_replacement().is_none());
+
+		// Found again after a restart
+		let replacement = signer.generate_replacement().unwrap();
+		let pending = signer.pending_replacement().unwrap();
+		assert_eq!(pending.get_public_key(), replacement.get_public_key());
+		signer.discard_replacement().unwrap();
+		assert!(signer.pending_replacement().is_none());
+
+		let replacement = signer.generate_replacement().unwrap();
+		let replaced = signer.replace_with(replacement.clone()).unwrap();
+		assert_eq!(replaced.get_public_key(), replacement.get_public_key());
+		assert_eq!(Signer::load(path, None).unwrap().get_public_key(), replacement.get_public_key());
+		assert!(signer.pending_replacement().is_none());
+		fs::remove_file(path).unwrap();
 	}
 }
@@ -0,0 +1,181 @@
+use crate::peripherals::{ Notifier, Tone };
+
+// Notification sequences for each outcome of a badge tap, kept in one place so they can be tested against a recording notifier
+
+fn error_tones() -> Vec<Tone> {
+	vec![
+		Tone::new(261.63, 500),
+		Tone::new(0.0, 200),
+		Tone::new(261.63, 500),
+	]
+}
+
+pub fn ready(notifier: &Notifier) {
+	notifier.flash_multiple(false, vec![500, 200, 100, 0]);
+	notifier.flash_multiple(true, vec![500, 200, 100, 0]);
+	notifier.beep(vec![
+		Tone::new(261.63, 500),
+		Tone::new(0.0, 200),
+		Tone::new(523.25, 100),
+	]);
+}
+
+pub fn no_tag(notifier: &Notifier) {
+	notifier.flash_multiple(false, vec![200, 100, 200, 0]);
+	notifier.beep(vec![
+		Tone::new(261.63, 500),
+	]);
+	notifier.scroll_text("No check-in tag defined by manager");
+}
+
+pub fn checked_in(notifier: &Notifier) {
+	notifier.flash(true, 500);
+	notifier.beep(vec![
+		Tone::new(1046.50, 100),
+	]);
+}
+
+/// `message` is usually how long ago they checked in
+pub fn already_checked_in(notifier: &Notifier, message: &str) {
+	notifier.flash(false, 500);
+	notifier.beep(error_tones());
+	notifier.scroll_text(message);
+}
+
+pub fn invalid_badge(notifier: &Notifier) {
+	notifier.flash(false, 500);
+	notifier.beep(error_tones());
+	notifier.scroll_text("Invalid user ID on badge");
+}
+
+/// The check-in API turned the check-in down, `message` says why
+pub fn rejected(notifier: &Notifier, message: &str) {
+	notifier.flash(false, 500);
+	notifier.beep(error_tones());
+	notifier.scroll_text(message);
+}
+
+pub fn queued(notifier: &Notifier, queue_length: usize) {
+	notifier.flash_multiple(true, vec![150, 100, 150, 0]);
+	notifier.beep(vec![
+		Tone::new(523.25, 100),
+		Tone::new(0.0, 100),
+		Tone::new(523.25, 100),
+	]);
+	notifier.scroll_text(&format!("Queued ({} offline)", queue_length));
+}
+
+pub fn api_error(notifier: &Notifier) {
+	notifier.flash(false, 500);
+	notifier.beep(error_tones());
+	notifier.scroll_text("API error");
+}
+
+pub fn read_error(notifier: &Notifier) {
+	notifier.flash_multiple(false, vec![200, 100, 200, 0]);
+	notifier.beep(vec![
+		Tone::new(261.63, 500),
+	]);
+	notifier.scroll_text("Try again");
+}
+
+/// Shown when the manager revokes our access and for every tap afterwards
+pub fn revoked(notifier: &Notifier) {
+	notifier.flash(false, 500);
+	notifier.beep(error_tones());
+	notifier.scroll_text("Revoked");
+}
+
+/// Sent from the manager UI so that someone can find this device in a room full of them
+pub fn identify(notifier: &Notifier, name: &str) {
+	notifier.flash_multiple(true, vec![150, 150, 150, 150, 150, 0]);
+	notifier.flash_multiple(false, vec![150, 150, 150, 150, 150, 0]);
+	notifier.beep(vec![
+		Tone::new(523.25, 150),
+		Tone::new(0.0, 100),
+		Tone::new(1046.50, 150),
+		Tone::new(0.0, 100),
+		Tone::new(523.25, 150),
+		Tone::new(0.0, 100),
+		Tone::new(1046.50, 150),
+	]);
+	notifier.scroll_text(name);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use crate::recording::Recording;
+	use super::*;
+
+	const TIMEOUT: Duration = Duration::from_secs(10);
+
+	#[test]
+	fn checked_in_flashes_success_and_chirps() {
+		let recording = Recording::new();
+		checked_in(&recording.notifier());
+		recording.wait_until_idle(TIMEOUT);
+
+		assert_eq!(recording.frames("success"), vec![Recording::ALL_ON, Recording::ALL_OFF]);
+		assert!(recording.frames("error").is_empty());
+		assert_eq!(recording.tones(), vec![(1046.50, 100)]);
+	}
+
+	#[test]
+	fn already_checked_in_flashes_error_then_scrolls() {
+		let recording = Recording::new();
+		already_checked_in(&recording.notifier(), "5 minutes ago");
+		recording.wait_until_idle(TIMEOUT);
+
+		let error_frames = recording.frames("error");
+		assert_eq!(&error_frames[..2], &[Recording::ALL_ON, Recording::ALL_OFF]);
+		// Text scrolls across both displays and ends blank
+		assert!(error_frames.len() > 2);
+		assert!(error_frames[2..].iter().any(|frame| *frame != Recording::ALL_OFF));
+		assert_eq!(*error_frames.last().unwrap(), Recording::ALL_OFF);
+		let success_frames = recording.frames("success");
+		assert!(success_frames.iter().any(|frame| *frame != Recording::ALL_OFF));
+		assert_eq!(*success_frames.last().unwrap(), Recording::ALL_OFF);
+		assert_eq!(success_frames.len(), error_frames.len() - 2);
+
+		assert_eq!(recording.tones(), vec![(261.63, 500), (0.0, 200), (261.63, 500)]);
+	}
+
+	#[test]
+	fn queued_blinks_success_twice() {
+		let recording = Recording::new();
+		queued(&recording.notifier(), 3);
+		recording.wait_until_idle(TIMEOUT);
+
+		let success_frames = recording.frames("success");
+		assert_eq!(&success_frames[..4], &[Recording::ALL_ON, Recording::ALL_OFF, Recording::ALL_ON, Recording::ALL_OFF]);
+		assert_eq!(recording.tones(), vec![(523.25, 100), (0.0, 100), (523.25, 100)]);
+	}
+
+	#[test]
+	fn read_error_blinks_error_display() {
+		let recording = Recording::new();
+		read_error(&recording.notifier());
+		recording.wait_until_idle(TIMEOUT);
+
+		let error_frames = recording.frames("error");
+		assert_eq!(&error_frames[..4], &[Recording::ALL_ON, Recording::ALL_OFF, Recording::ALL_ON, Recording::ALL_OFF]);
+		assert_eq!(recording.tones(), vec![(261.63, 500)]);
+	}
+
+	#[test]
+	fn scroll_text_leaves_displays_blank() {
+		let recording = Recording::new();
+		let mut right = recording.display("right");
+		let mut left = recording.display("left");
+		crate::peripherals::scroll_text("Hi", &mut [&mut right, &mut left], 0).unwrap();
+
+		let right_frames = recording.frames("right");
+		let left_frames = recording.frames("left");
+		// "H" is four columns, "i" three and a blank column after each, then both displays' worth of columns to slide off
+		assert_eq!(right_frames.len(), 4 + 1 + 3 + 1 + 16);
+		assert_eq!(left_frames.len(), right_frames.len());
+		assert_eq!(*right_frames.last().unwrap(), Recording::ALL_OFF);
+		assert_eq!(*left_frames.last().unwrap(), Recording::ALL_OFF);
+	}
+}
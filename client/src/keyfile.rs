@@ -0,0 +1,302 @@
+use std::{ fmt, fs, io };
+use std::io::Write;
+use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+use rand::RngCore;
+use rand::rngs::OsRng;
+use chacha20poly1305::{ Key, XChaCha20Poly1305, XNonce };
+use chacha20poly1305::aead::{ Aead, NewAead, Payload };
+
+// Key files start with MAGIC, the format version and a flags byte.
+//
+// Plain (flags 0):     header | 64 byte keypair | SHA-256 of everything before it
+// Encrypted (flags 1): header | Argon2id memory (KiB, u32 BE) | passes (u32 BE) | 16 byte salt | 24 byte nonce
+//                      | keypair encrypted with XChaCha20-Poly1305 (the bytes before it are authenticated too)
+//
+// Files from before the format existed are the raw 64 byte keypair and get rewritten when loaded.
+const MAGIC: &'static [u8] = b"CKEY";
+const VERSION: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 1;
+const HEADER_LENGTH: usize = 6;
+const KEY_LENGTH: usize = ed25519_dalek::KEYPAIR_LENGTH;
+const CHECKSUM_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+const ENCRYPTED_HEADER_LENGTH: usize = HEADER_LENGTH + 4 + 4 + SALT_LENGTH + NONCE_LENGTH;
+
+/// Argon2id cost used for new files. Stored in each file so that it can be raised later without breaking old ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cost {
+	/// KiB
+	memory: u32,
+	passes: u32,
+}
+
+// Takes about a second on a Pi 3 without running a Pi Zero out of memory
+const DEFAULT_COST: Cost = Cost { memory: 19 * 1024, passes: 2 };
+// Files are untrusted so don't let one make us allocate gigabytes
+const MAX_MEMORY_COST: u32 = 256 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+	IO(io::Error),
+	/// Not a key file or it has been modified
+	Corrupt,
+	/// Encrypted but no passphrase or machine binding is configured
+	SecretRequired,
+	/// Passphrase or machine doesn't match the one the file was encrypted for (or the ciphertext was modified)
+	WrongSecret,
+}
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Self {
+		Error::IO(err)
+	}
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::IO(err) => write!(f, "{}", err),
+			Error::Corrupt => write!(f, "Key file is corrupt or has been modified"),
+			Error::SecretRequired => write!(f, "Key file is encrypted but neither key_passphrase nor bind_key_to_machine is configured"),
+			Error::WrongSecret => write!(f, "Key file can't be decrypted with the configured key_passphrase on this machine"),
+		}
+	}
+}
+
+/// Builds the secret that key files are encrypted with from a passphrase and/or this machine's ID. None if neither is used.
+pub fn secret(passphrase: Option<&str>, bind_to_machine: bool) -> Result<Option<Vec<u8>>, String> {
+	let mut secret = Vec::new();
+	if let Some(passphrase) = passphrase {
+		secret.extend_from_slice(b"passphrase:");
+		secret.extend_from_slice(passphrase.as_bytes());
+		secret.push(0);
+	}
+	if bind_to_machine {
+		let id = machine_id().ok_or("bind_key_to_machine is set but there's no CPU serial number (only Raspberry Pis have one)")?;
+		secret.extend_from_slice(b"machine:");
+		secret.extend_from_slice(id.as_bytes());
+		secret.push(0);
+	}
+	Ok(if secret.is_empty() { None } else { Some(secret) })
+}
+
+// The Pi's serial number is burned into the SoC so a copied SD card is useless without the Pi it came from.
+// Nothing stored on the card (like /etc/machine-id) can stand in for it.
+fn machine_id() -> Option<String> {
+	fs::read_to_string("/proc/cpuinfo").ok().and_then(|cpuinfo| {
+		cpuinfo.lines()
+			.filter(|line| line.starts_with("Serial"))
+			.filter_map(|line| line.splitn(2, ':').nth(1))
+			.map(|serial| serial.trim().to_owned())
+			.find(|serial| !serial.is_empty() && serial.chars().any(|c| c != '0'))
+	})
+}
+
+/// Reads the keypair in `path`. Files in an older format or not encrypted the way `secret` asks for are rewritten,
+/// and permissions are tightened so that only we can read the file.
+pub fn load(path: &str, secret: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+	let file = fs::read(path)?;
+	let legacy = file.len() == KEY_LENGTH && !file.starts_with(MAGIC);
+	let (key, encrypted) = if legacy {
+		println!("Upgrading {} to the current key file format", path);
+		(file, false)
+	}
+	else {
+		decode(&file, secret)?
+	};
+	// An encrypted file without a secret configured has already failed to decode
+	if !legacy && encrypted == secret.is_some() {
+		let mode = fs::metadata(path)?.permissions().mode();
+		if mode & 0o077 != 0 {
+			fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+		}
+		return Ok(key);
+	}
+	if secret.is_some() {
+		println!("Encrypting {}", path);
+	}
+	save(path, &key, secret)?;
+	Ok(key)
+}
+
+/// Writes `key` to `path` (encrypted if there's a secret) so that it's only readable by us
+pub fn save(path: &str, key: &[u8], secret: Option<&[u8]>) -> io::Result<()> {
+	save_with_cost(path, key, secret, DEFAULT_COST)
+}
+
+fn save_with_cost(path: &str, key: &[u8], secret: Option<&[u8]>, cost: Cost) -> io::Result<()> {
+	assert_eq!(key.len(), KEY_LENGTH, "Keypair is the wrong length");
+	let contents = match secret {
+		Some(secret) => encrypt(key, secret, cost),
+		None => {
+			let mut contents = header(0);
+			contents.extend_from_slice(key);
+			let checksum = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &contents);
+			contents.extend_from_slice(&checksum);
+			contents
+		},
+	};
+	// Replaced in one step so that a crash or power loss never leaves us with half a key
+	let temp_path = format!("{}.tmp", path);
+	{
+		let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path)?;
+		// In case it was left over with looser permissions
+		file.set_permissions(fs::Permissions::from_mode(0o600))?;
+		file.write_all(&contents)?;
+		file.sync_all()?;
+	}
+	fs::rename(&temp_path, path)
+}
+
+fn header(flags: u8) -> Vec<u8> {
+	let mut header = MAGIC.to_vec();
+	header.push(VERSION);
+	header.push(flags);
+	header
+}
+
+fn derive_key(secret: &[u8], salt: &[u8], cost: Cost) -> Result<Vec<u8>, Error> {
+	let config = argon2::Config {
+		variant: argon2::Variant::Argon2id,
+		mem_cost: cost.memory,
+		time_cost: cost.passes,
+		lanes: 1,
+		hash_length: 32,
+		..argon2::Config::default()
+	};
+	argon2::hash_raw(secret, salt, &config).map_err(|_| Error::Corrupt)
+}
+
+fn encrypt(key: &[u8], secret: &[u8], cost: Cost) -> Vec<u8> {
+	let mut csprng = OsRng::new().unwrap();
+	let mut salt = [0; SALT_LENGTH];
+	let mut nonce = [0; NONCE_LENGTH];
+	csprng.fill_bytes(&mut salt);
+	csprng.fill_bytes(&mut nonce);
+
+	let mut contents = header(FLAG_ENCRYPTED);
+	contents.extend_from_slice(&cost.memory.to_be_bytes());
+	contents.extend_from_slice(&cost.passes.to_be_bytes());
+	contents.extend_from_slice(&salt);
+	contents.extend_from_slice(&nonce);
+	let encryption_key = derive_key(secret, &salt, cost).expect("Invalid Argon2 parameters");
+	let cipher = XChaCha20Poly1305::new(Key::from_slice(&encryption_key));
+	let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: key, aad: &contents })
+		.expect("Could not encrypt key");
+	contents.extend_from_slice(&ciphertext);
+	contents
+}
+
+/// Returns the keypair and whether it was encrypted
+fn decode(file: &[u8], secret: Option<&[u8]>) -> Result<(Vec<u8>, bool), Error> {
+	if file.len() < HEADER_LENGTH || &file[..MAGIC.len()] != MAGIC || file[4] != VERSION {
+		return Err(Error::Corrupt);
+	}
+	match file[5] {
+		0 => {
+			if file.len() != HEADER_LENGTH + KEY_LENGTH + CHECKSUM_LENGTH {
+				return Err(Error::Corrupt);
+			}
+			let (contents, checksum) = file.split_at(HEADER_LENGTH + KEY_LENGTH);
+			if crypto_hash::digest(crypto_hash::Algorithm::SHA256, contents) != checksum {
+				return Err(Error::Corrupt);
+			}
+			Ok((contents[HEADER_LENGTH..].to_vec(), false))
+		},
+		FLAG_ENCRYPTED => {
+			if file.len() != ENCRYPTED_HEADER_LENGTH + KEY_LENGTH + TAG_LENGTH {
+				return Err(Error::Corrupt);
+			}
+			let secret = secret.ok_or(Error::SecretRequired)?;
+			let (header, ciphertext) = file.split_at(ENCRYPTED_HEADER_LENGTH);
+			let read_u32 = |offset: usize| u32::from_be_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]);
+			let cost = Cost { memory: read_u32(HEADER_LENGTH), passes: read_u32(HEADER_LENGTH + 4) };
+			if cost.memory > MAX_MEMORY_COST {
+				return Err(Error::Corrupt);
+			}
+			let salt = &header[HEADER_LENGTH + 8..HEADER_LENGTH + 8 + SALT_LENGTH];
+			let nonce = &header[ENCRYPTED_HEADER_LENGTH - NONCE_LENGTH..];
+			let encryption_key = derive_key(secret, salt, cost)?;
+			let cipher = XChaCha20Poly1305::new(Key::from_slice(&encryption_key));
+			let key = cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+				.map_err(|_| Error::WrongSecret)?;
+			Ok((key, true))
+		},
+		_ => Err(Error::Corrupt),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use super::*;
+
+	// Keeps tests fast. Real files use DEFAULT_COST.
+	const TEST_COST: Cost = Cost { memory: 64, passes: 1 };
+	const KEY: [u8; KEY_LENGTH] = [7; KEY_LENGTH];
+
+	fn temp_path(name: &str) -> String {
+		let path: PathBuf = std::env::temp_dir().join(format!("checkin-keyfile-{}-{}", std::process::id(), name));
+		let _ = fs::remove_file(&path);
+		path.to_str().unwrap().to_owned()
+	}
+
+	fn mode(path: &str) -> u32 {
+		fs::metadata(path).unwrap().permissions().mode() & 0o777
+	}
+
+	#[test]
+	fn round_trips_plain_and_encrypted() {
+		let path = temp_path("round-trip");
+		save_with_cost(&path, &KEY, None, TEST_COST).unwrap();
+		assert_eq!(mode(&path), 0o600);
+		assert_eq!(load(&path, None).unwrap(), KEY.to_vec());
+
+		save_with_cost(&path, &KEY, Some(b"hunter2"), TEST_COST).unwrap();
+		assert!(!fs::read(&path).unwrap().windows(KEY_LENGTH).any(|window| window == &KEY[..]));
+		assert_eq!(load(&path, Some(b"hunter2")).unwrap(), KEY.to_vec());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn rejects_wrong_or_missing_secret() {
+		let path = temp_path("wrong-secret");
+		save_with_cost(&path, &KEY, Some(b"hunter2"), TEST_COST).unwrap();
+		assert!(match load(&path, Some(b"hunter3")) { Err(Error::WrongSecret) => true, _ => false });
+		assert!(match load(&path, None) { Err(Error::SecretRequired) => true, _ => false });
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn detects_modification() {
+		let path = temp_path("tampered");
+		save_with_cost(&path, &KEY, None, TEST_COST).unwrap();
+		let mut file = fs::read(&path).unwrap();
+		file[HEADER_LENGTH] ^= 1;
+		fs::write(&path, &file).unwrap();
+		assert!(match load(&path, None) { Err(Error::Corrupt) => true, _ => false });
+
+		save_with_cost(&path, &KEY, Some(b"hunter2"), TEST_COST).unwrap();
+		let mut file = fs::read(&path).unwrap();
+		// Salt is authenticated along with the ciphertext
+		file[HEADER_LENGTH + 8] ^= 1;
+		fs::write(&path, &file).unwrap();
+		assert!(load(&path, Some(b"hunter2")).is_err());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn migrates_legacy_files() {
+		let path = temp_path("legacy");
+		fs::write(&path, &KEY[..]).unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		assert_eq!(load(&path, None).unwrap(), KEY.to_vec());
+		assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+		assert_eq!(mode(&path), 0o600);
+
+		// Turning on encryption later rewrites the plain file
+		assert_eq!(load(&path, Some(b"hunter2")).unwrap(), KEY.to_vec());
+		assert!(match load(&path, None) { Err(Error::SecretRequired) => true, _ => false });
+		fs::remove_file(&path).unwrap();
+	}
+}
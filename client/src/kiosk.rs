@@ -0,0 +1,287 @@
+use std::sync::Arc;
+use chrono::DateTime;
+use hackgt_nfc::api::CheckinAPI;
+use crate::api::ManagerAPI;
+use crate::attendees::{ AttendeeCache, LocalDecision };
+use crate::feedback;
+use crate::peripherals::Notifier;
+use crate::queue::{ CheckInQueue, PendingCheckIn };
+
+/// How the check-in API answered a badge tap
+#[derive(Clone, Debug)]
+pub enum CheckInResult {
+	CheckedIn { name: String },
+	/// `last_checkin` is when they were last checked in to the tag, if known
+	AlreadyCheckedIn { last_checkin: Option<String> },
+}
+
+#[derive(Clone, Debug)]
+pub enum CheckInError {
+	/// The check-in API answered and turned the check-in down
+	Rejected(&'static str),
+	/// The check-in API couldn't be reached
+	Unreachable(String),
+}
+
+/// What a kiosk needs from the manager and the check-in API to handle a badge tap
+pub trait Backend {
+	fn is_revoked(&self) -> bool;
+	fn current_tag(&self) -> Option<String>;
+	fn device_name(&self) -> String;
+	fn check_in(&self, badge_id: &str, tag: &str) -> Result<CheckInResult, CheckInError>;
+}
+
+/// The manager and checkin2
+pub struct LiveBackend {
+	pub manager: Arc<ManagerAPI>,
+	pub api: Arc<CheckinAPI>,
+}
+
+impl Backend for LiveBackend {
+	fn is_revoked(&self) -> bool {
+		self.manager.is_revoked()
+	}
+
+	fn current_tag(&self) -> Option<String> {
+		self.manager.current_tag.read().unwrap().clone()
+	}
+
+	fn device_name(&self) -> String {
+		self.manager.get_name()
+	}
+
+	fn check_in(&self, badge_id: &str, tag: &str) -> Result<CheckInResult, CheckInError> {
+		match self.api.check_in(badge_id, tag) {
+			Ok((true, user, _)) => Ok(CheckInResult::CheckedIn { name: user.name }),
+			Ok((false, _, tag)) => Ok(CheckInResult::AlreadyCheckedIn {
+				last_checkin: tag.last_successful_checkin.map(|checkin| checkin.checked_in_date),
+			}),
+			Err(hackgt_nfc::api::Error::Message(message)) => Err(CheckInError::Rejected(message)),
+			Err(err) => Err(CheckInError::Unreachable(format!("{:?}", err))),
+		}
+	}
+}
+
+/// Everything needed to turn a badge tap into a check-in
+pub struct Kiosk {
+	pub backend: Box<dyn Backend>,
+	pub notifier: Arc<Notifier>,
+	pub checkin_queue: Arc<CheckInQueue>,
+	pub attendee_cache: Arc<AttendeeCache>,
+}
+
+impl Kiosk {
+	pub fn check_in(&self, id: String) {
+		let notifier = &self.notifier;
+		if self.backend.is_revoked() {
+			feedback::revoked(notifier);
+			return;
+		}
+		let current_tag = match self.backend.current_tag() {
+			Some(tag) => tag,
+			None => {
+				feedback::no_tag(notifier);
+				return;
+			}
+		};
+
+		match self.backend.check_in(&id, &current_tag) {
+			Ok(CheckInResult::CheckedIn { name }) => {
+				feedback::checked_in(notifier);
+				println!("Checked in {}", &name);
+			},
+			Ok(CheckInResult::AlreadyCheckedIn { last_checkin: Some(last_checkin) }) => {
+				feedback::already_checked_in(notifier, &get_relative_time(&last_checkin));
+			},
+			Ok(CheckInResult::AlreadyCheckedIn { last_checkin: None }) => {
+				feedback::already_checked_in(notifier, "Already checked in");
+			},
+			Err(CheckInError::Rejected("Invalid user ID on badge")) => {
+				feedback::invalid_badge(notifier);
+			},
+			// The API answered and turned this down, so queueing it would only get it dropped when replayed
+			Err(CheckInError::Rejected(message)) => {
+				println!("Check in rejected: {}", message);
+				feedback::rejected(notifier, message);
+			},
+			// Only failures to reach the API get queued
+			Err(CheckInError::Unreachable(err)) => {
+				println!("Check in failed: {}", err);
+				match self.attendee_cache.decide(&id, &current_tag) {
+					LocalDecision::AlreadyCheckedIn => {
+						feedback::already_checked_in(notifier, "Already checked in");
+						return;
+					},
+					LocalDecision::Invalid => {
+						feedback::invalid_badge(notifier);
+						return;
+					},
+					// Either validated against the cached snapshot or we have nothing to check against
+					LocalDecision::Success | LocalDecision::Unknown => {},
+				}
+				let checkin = PendingCheckIn {
+					badge_id: id,
+					tag: current_tag,
+					timestamp: chrono::Utc::now().to_rfc3339(),
+					device: self.backend.device_name(),
+				};
+				match self.checkin_queue.push(checkin) {
+					Ok(_) => {
+						feedback::queued(notifier, self.checkin_queue.len());
+					},
+					Err(err) => {
+						eprintln!("Failed to queue check in: {:?}", err);
+						feedback::api_error(notifier);
+					}
+				}
+			}
+		};
+	}
+}
+
+fn get_relative_time(iso_time: &str) -> String {
+	let time = match DateTime::parse_from_rfc3339(iso_time) {
+		Ok(time) => time,
+		Err(_) => return String::from("invalid time ago"),
+	};
+	let now = chrono::Local::now();
+	let duration = now.signed_duration_since(time);
+
+	fn pluralizer(num: i64, label: &str) -> String {
+		format!("{} {}{} ago", num, label, if num == 1 { "" } else { "s" })
+	}
+
+	let weeks = duration.num_weeks();
+	if weeks > 0 {
+		return pluralizer(weeks, "week");
+	}
+	let days = duration.num_days();
+	if days > 0 {
+		return pluralizer(days, "day");
+	}
+	let hours = duration.num_hours();
+	if hours > 0 {
+		return pluralizer(hours, "hour");
+	}
+	let minutes = duration.num_minutes();
+	if minutes > 0 {
+		return pluralizer(minutes, "minute");
+	}
+	pluralizer(duration.num_seconds(), "second")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::sync::Mutex;
+	use std::time::Duration;
+	use crate::recording::Recording;
+	use super::*;
+
+	const TIMEOUT: Duration = Duration::from_secs(10);
+	const ERROR_TONES: [(f64, u64); 3] = [(261.63, 500), (0.0, 200), (261.63, 500)];
+
+	struct FakeBackend {
+		revoked: bool,
+		response: Result<CheckInResult, CheckInError>,
+		calls: Mutex<Vec<(String, String)>>,
+	}
+
+	impl Backend for Arc<FakeBackend> {
+		fn is_revoked(&self) -> bool {
+			self.revoked
+		}
+
+		fn current_tag(&self) -> Option<String> {
+			Some("lunch".to_owned())
+		}
+
+		fn device_name(&self) -> String {
+			"test-device".to_owned()
+		}
+
+		fn check_in(&self, badge_id: &str, tag: &str) -> Result<CheckInResult, CheckInError> {
+			self.calls.lock().unwrap().push((badge_id.to_owned(), tag.to_owned()));
+			self.response.clone()
+		}
+	}
+
+	struct Tap {
+		recording: Recording,
+		backend: Arc<FakeBackend>,
+		queue: Arc<CheckInQueue>,
+	}
+
+	fn tap(name: &str, revoked: bool, response: Result<CheckInResult, CheckInError>) -> Tap {
+		let path = std::env::temp_dir().join(format!("checkin-kiosk-{}-{}", std::process::id(), name));
+		let _ = fs::remove_file(&path);
+		let recording = Recording::new();
+		let backend = Arc::new(FakeBackend {
+			revoked,
+			response,
+			calls: Mutex::new(Vec::new()),
+		});
+		let queue = Arc::new(CheckInQueue::load(path.to_str().unwrap()));
+		let kiosk = Kiosk {
+			backend: Box::new(Arc::clone(&backend)),
+			notifier: Arc::new(recording.notifier()),
+			checkin_queue: Arc::clone(&queue),
+			attendee_cache: Arc::new(AttendeeCache::new(path.with_extension("attendees").to_str().unwrap(), 60, None)),
+		};
+		kiosk.check_in("badge".to_owned());
+		recording.wait_until_idle(TIMEOUT);
+		let _ = fs::remove_file(&path);
+		Tap { recording, backend, queue }
+	}
+
+	#[test]
+	fn checks_in_valid_badges() {
+		let tap = tap("success", false, Ok(CheckInResult::CheckedIn { name: "George P. Burdell".to_owned() }));
+		assert_eq!(*tap.backend.calls.lock().unwrap(), vec![("badge".to_owned(), "lunch".to_owned())]);
+		assert_eq!(tap.recording.frames("success")[0], Recording::ALL_ON);
+		assert!(tap.recording.frames("error").is_empty());
+		assert_eq!(tap.recording.tones(), vec![(1046.50, 100)]);
+		assert_eq!(tap.queue.len(), 0);
+	}
+
+	#[test]
+	fn turns_away_badges_already_checked_in() {
+		let tap = tap("already", false, Ok(CheckInResult::AlreadyCheckedIn { last_checkin: Some(chrono::Utc::now().to_rfc3339()) }));
+		assert_eq!(tap.recording.frames("error")[0], Recording::ALL_ON);
+		assert_eq!(tap.recording.tones(), ERROR_TONES.to_vec());
+		assert_eq!(tap.queue.len(), 0);
+	}
+
+	#[test]
+	fn turns_away_invalid_badges() {
+		let tap = tap("invalid", false, Err(CheckInError::Rejected("Invalid user ID on badge")));
+		assert_eq!(tap.recording.frames("error")[0], Recording::ALL_ON);
+		assert_eq!(tap.recording.tones(), ERROR_TONES.to_vec());
+		assert_eq!(tap.queue.len(), 0);
+	}
+
+	#[test]
+	fn shows_other_rejections_without_queueing() {
+		let tap = tap("rejected", false, Err(CheckInError::Rejected("Tag not found")));
+		assert_eq!(tap.recording.frames("error")[0], Recording::ALL_ON);
+		assert_eq!(tap.recording.tones(), ERROR_TONES.to_vec());
+		assert_eq!(tap.queue.len(), 0);
+	}
+
+	#[test]
+	fn queues_check_ins_while_offline() {
+		let tap = tap("queued", false, Err(CheckInError::Unreachable("timed out".to_owned())));
+		assert_eq!(tap.queue.len(), 1);
+		assert_eq!(tap.recording.frames("success")[..4], [Recording::ALL_ON, Recording::ALL_OFF, Recording::ALL_ON, Recording::ALL_OFF]);
+		assert_eq!(tap.recording.tones(), vec![(523.25, 100), (0.0, 100), (523.25, 100)]);
+	}
+
+	#[test]
+	fn revoked_devices_do_not_check_in() {
+		let tap = tap("revoked", true, Ok(CheckInResult::CheckedIn { name: "George P. Burdell".to_owned() }));
+		assert!(tap.backend.calls.lock().unwrap().is_empty());
+		assert_eq!(tap.recording.frames("error")[0], Recording::ALL_ON);
+		assert_eq!(tap.recording.tones(), ERROR_TONES.to_vec());
+		assert_eq!(tap.queue.len(), 0);
+	}
+}
@@ -1,19 +1,63 @@
 use hackgt_nfc::api::CheckinAPI;
-use hackgt_nfc::nfc::{ handle_cards, NFCBadge };
-use chrono::DateTime;
-use std::sync::Arc;
+use std::sync::{ Arc, mpsc };
 
 mod api;
 use api::{ ManagerAPI, ManagedStatus };
 mod crypto;
+mod keyfile;
 mod peripherals;
+use peripherals::Notifier;
+mod feedback;
+#[cfg(test)]
+mod recording;
+mod queue;
+use queue::CheckInQueue;
+mod attendees;
+use attendees::AttendeeCache;
+mod kiosk;
+use kiosk::{ Kiosk, LiveBackend };
+mod simulator;
+mod cards;
+use cards::{ CardSource, CardEvent, PCSCSource, ReplaySource, SocketSource };
+mod config;
+use config::Config;
+mod releases;
+use releases::{ Updater, Watchdog };
+mod startup;
 
 fn main() {
+    // Run on a build machine to prepare a release for the manager to serve
+    if std::env::args().any(|arg| arg == "--sign-release") {
+        sign_release();
+        return;
+    }
     println!("--- START UP ---");
+    // Runs on a laptop with the displays and buzzer drawn in the terminal and a local manager
+    let simulate = std::env::args().any(|arg| arg == "--simulate");
+    // Plays back a file of scripted taps (see cards::ReplaySource)
+    let replay_file = std::env::args().skip_while(|arg| arg != "--replay").nth(1);
+    // Started before anything that could hang or crash, including reading the config, so that a bad update always gets rolled back
+    let watchdog = Watchdog::start();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(errors) => show_config_errors(&errors, simulate),
+    };
+    let key_secret = match keyfile::secret(config.key_passphrase.as_ref().map(String::as_str), config.bind_key_to_machine) {
+        Ok(secret) => secret,
+        Err(err) => show_config_errors(&[err], simulate),
+    };
+    // Loaded before the displays are started so that show_config_errors can use them
+    let signer = match crypto::Signer::load(&config.key_file, key_secret.as_ref().map(Vec::as_slice)) {
+        Ok(signer) => signer,
+        Err(err) => show_config_errors(&[format!("{}: {}", &config.key_file, err)], simulate),
+    };
+    let updater = Arc::new(Updater::new(config.release_key.as_ref().map(String::as_str), config.rollback_minutes, &watchdog));
     // We'll be using this notifier on the main thread + the tag update thread so it needs to be behind an Arc
-    let notifier_arc = Arc::new(peripherals::Notifier::start(0x70, 0x71, 18));
+    let notifier_arc = Arc::new(start_notifier(&config, simulate));
     let notifier = notifier_arc.clone();
-    notifier.setup_reset_button();
+    if !simulate {
+        notifier.setup_reset_button(config.reset_button_pin);
+    }
     notifier.flash_alternate(vec![150, 150, 150, 150, 150, 150], &notifier_arc);
 
     let exit_with_error = |message: &str| -> ! {
@@ -25,28 +69,23 @@ fn main() {
         }
     };
 
-    // Bootstrap connection to manager
-    // Network might not come up right away so keep trying
-    let (manager, result) = loop {
-        let manager = ManagerAPI::new();
-        let result = manager.initialize();
-        if result.is_ok() {
-            break (manager, result);
-        }
-        const WAIT_TIME: u64 = 5; // seconds
-        const FLASH_TIME: u64 = 500; // milliseconds
-        let start = std::time::SystemTime::now();
-        while start.elapsed().unwrap().as_secs() < WAIT_TIME {
-            notifier.flash_alternate(vec![FLASH_TIME, FLASH_TIME], &notifier_arc);
-            std::thread::sleep(std::time::Duration::from_millis(FLASH_TIME * 2));
+    let manager_key = match ManagerAPI::load_server_key(config.manager_key.as_ref().map(String::as_str)) {
+        Ok(manager_key) => manager_key,
+        Err(err) => {
+            eprintln!("{}", &err);
+            exit_with_error(&err);
         }
     };
-    let manager_arc = Arc::new(manager);
+
+    // Bootstrap connection to manager
+    // Network might not come up right away and we might still need approval so keep trying
+    let signer = startup::finish_key_rotation(signer, &config.manager_url, manager_key, &notifier);
+    let manager_arc = Arc::new(ManagerAPI::new(&config.manager_url, signer.clone(), manager_key));
     let manager = Arc::clone(&manager_arc);
-    let signer = crypto::Signer::load();
+    let status = startup::wait_for_authorization(&manager, &signer, &notifier_arc);
 
-    let api: CheckinAPI = match result {
-        Ok(ManagedStatus::AuthorizedHasCredentials) => {
+    let api: CheckinAPI = match status {
+        ManagedStatus::AuthorizedHasCredentials => {
             // Use existing credentials
             let credentials = signer.get_api_credentials();
             match CheckinAPI::login(&credentials.username, &credentials.password) {
@@ -69,7 +108,7 @@ fn main() {
                 }
             }
         },
-        Ok(ManagedStatus::AuthorizedNoCredentials) => {
+        ManagedStatus::AuthorizedNoCredentials => {
             // Request server create an account with our credentials
             let response = manager.create_credentials().unwrap();
             if !response.success {
@@ -80,152 +119,125 @@ fn main() {
             let credentials = signer.get_api_credentials();
             CheckinAPI::login(&credentials.username, &credentials.password).expect("Invalid credentials after server apparently created our account")
         },
-        Ok(ManagedStatus::Unauthorized) => {
-            eprintln!("Check-in instance <{}> has been denied access in the manager UI", manager.get_name());
-            exit_with_error("Denied access in manager UI");
-        },
-        Ok(ManagedStatus::Pending) => {
-            eprintln!("Check-in instance <{}> must be approved in the manager UI before use", manager.get_name());
-            exit_with_error("Must approve device in manager UI before use");
-        },
-        Err(err) => {
-            let err = format!("{:?}", err);
-            eprintln!("Unhandled error: {}", &err);
-            notifier.scroll_text("Failed to get status from manager (offline?)");
-            exit_with_error(&err);
-        }
+        ManagedStatus::Pending | ManagedStatus::Unauthorized => unreachable!("Startup only finishes once we're authorized"),
     };
+    // Check-ins made while the check-in API is unreachable are saved and sent later
+    let api = Arc::new(api);
+    let checkin_queue = Arc::new(CheckInQueue::load("./checkins.journal"));
+    CheckInQueue::start_replaying(&checkin_queue, &api, &notifier_arc);
+    // Lets us reject invalid or duplicate badges even while check-ins are being queued
+    let attendee_cache = Arc::new(AttendeeCache::load("./attendees.json", config.snapshot_max_age_minutes, ManagerAPI::configured_server_key(config.manager_key.as_ref().map(String::as_str))));
+    AttendeeCache::start_refreshing(&attendee_cache, &manager_arc, 120);
+
     // Spawns a thread to check for tag updates
-    manager.start_polling_for_tag(30, notifier_arc.clone());
-    notifier.setup_tag_button(&manager_arc, &notifier_arc);
+    manager.start_polling_for_tag(config.poll_interval, notifier_arc.clone());
+    manager.start_listening_for_updates(notifier_arc.clone());
+    Updater::start_installing(&updater, &manager_arc);
+    if !simulate {
+        notifier.setup_tag_button(config.tag_button_pin, &manager_arc, &notifier_arc);
+    }
 
     // Signify that we're logged in and ready to go
-    notifier.flash_multiple(false, vec![500, 200, 100, 0]);
-    notifier.flash_multiple(true, vec![500, 200, 100, 0]);
-    notifier.beep(vec![
-        peripherals::Tone::new(261.63, 500),
-        peripherals::Tone::new(0.0, 200),
-        peripherals::Tone::new(523.25, 100),
-    ]);
-
-    // Set up card polling
-    let handler_thread = handle_cards(move |card, _reader, _reader_index| {
-        let badge = NFCBadge::new(&card);
-        badge.set_buzzer(false).unwrap();
+    feedback::ready(&notifier);
+    watchdog.started();
+    // Shows the idle message set in the manager (if any) after a minute without taps
+    Notifier::start_idle_messages(&notifier_arc, 60);
 
-        let current_tag = Arc::clone(&manager.current_tag);
-        let current_tag = current_tag.read().unwrap();
+    let kiosk = Kiosk {
+        backend: Box::new(LiveBackend {
+            manager: Arc::clone(&manager_arc),
+            api,
+        }),
+        notifier: Arc::clone(&notifier_arc),
+        checkin_queue,
+        attendee_cache,
+    };
 
-        // THIS IS SLOWWWWW
-        // My 3:40am guess is that the notifier is causing some kind of hold on the &card argument
-        // fake news
-        // my 3:42am knowledges says that this is somehow the cause:
-        // badge.get_user_id().unwrap();
-        // Only seems to be a problem on Linux (pcsclite)
-        // I ran the same code on Windows and it was significantly faster
+    let (events, card_events) = mpsc::channel();
+    let mut sources: Vec<Box<dyn CardSource>> = Vec::new();
+    if simulate {
+        // Badge taps are typed in or sent over a socket instead
+        simulator::read_stdin(events.clone(), &manager_arc, &notifier_arc);
+        sources.push(Box::new(SocketSource::new(simulator::SOCKET_PATH)));
+        manager.reader_connected.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+    else {
+        sources.push(Box::new(PCSCSource));
+    }
+    if let Some(replay_file) = replay_file {
+        sources.push(Box::new(ReplaySource::new(&replay_file)));
+    }
+    for source in sources {
+        source.start(events.clone());
+    }
+    drop(events);
 
-        match badge.get_user_id() {
-            Ok(_) if current_tag.is_none() => {
-                notifier.flash_multiple(false, vec![200, 100, 200, 0]);
-                notifier.beep(vec![
-                    peripherals::Tone::new(261.63, 500),
-                ]);
-                notifier.scroll_text("No check-in tag defined by manager");
+    for event in card_events {
+        match event {
+            CardEvent::Tap(id) => kiosk.check_in(id),
+            CardEvent::ReadError(err) => {
+                println!("Error getting user ID: {}", err);
+                feedback::read_error(&notifier);
             },
-            Ok(id) => {
-                match api.check_in(&id, current_tag.as_ref().unwrap()) {
-                    Ok((success, user, tag)) => {
-                        if success {
-                            notifier.flash(true, 500);
-                            notifier.beep(vec![
-                                peripherals::Tone::new(1046.50, 100),
-                            ]);
-                            println!("Checked in {}", &user.name);
-                        }
-                        else {
-                            notifier.flash(false, 500);
-                            notifier.beep(vec![
-                                peripherals::Tone::new(261.63, 500),
-                                peripherals::Tone::new(0.0, 200),
-                                peripherals::Tone::new(261.63, 500),
-                            ]);
-                            if let Some(last_checkin) = tag.last_successful_checkin {
-                                let time = get_relative_time(&last_checkin.checked_in_date);
-                                notifier.scroll_text(&time);
-                            }
-                            else {
-                                notifier.scroll_text("Already checked in");
-                            }
-                        }
-                    },
-                    Err(hackgt_nfc::api::Error::Message("Invalid user ID on badge")) => {
-                        notifier.flash(false, 500);
-                        notifier.beep(vec![
-                            peripherals::Tone::new(261.63, 500),
-                            peripherals::Tone::new(0.0, 200),
-                            peripherals::Tone::new(261.63, 500),
-                        ]);
-                        notifier.scroll_text("Invalid user ID on badge");
-                    },
-                    Err(_err) => {
-                        notifier.flash(false, 500);
-                        notifier.beep(vec![
-                            peripherals::Tone::new(261.63, 500),
-                            peripherals::Tone::new(0.0, 200),
-                            peripherals::Tone::new(261.63, 500),
-                        ]);
-                        notifier.scroll_text("API error");
-                    }
-                };
+            CardEvent::ReaderChanged(added) => {
+                manager.reader_connected.store(added, std::sync::atomic::Ordering::SeqCst);
+                if added {
+                    notifier.scroll_text_speed("Reader connected", 10);
+                }
+                else {
+                    notifier.scroll_text_speed("Reader disconnected", 10);
+                }
             },
-            Err(err) => {
-                println!("Error getting user ID: {:?}", err);
-                notifier.flash_multiple(false, vec![200, 100, 200, 0]);
-                notifier.beep(vec![
-                    peripherals::Tone::new(261.63, 500),
-                ]);
-                notifier.scroll_text("Try again");
-            }
-        };
-    }, move |_reader, added| {
-        let notifier = notifier_arc.clone();
-        if added {
-            notifier.scroll_text_speed("Reader connected", 10);
-        }
-        else {
-            notifier.scroll_text_speed("Reader disconnected", 10);
         }
-    });
-    handler_thread.join().unwrap();
+    }
+    eprintln!("All card sources have stopped");
 }
 
-fn get_relative_time(iso_time: &str) -> String {
-    let time = match DateTime::parse_from_rfc3339(iso_time) {
-        Ok(time) => time,
-        Err(_) => return String::from("invalid time ago"),
-    };
-    let now = chrono::Local::now();
-    let duration = now.signed_duration_since(time);
-
-    fn pluralizer(num: i64, label: &str) -> String {
-        format!("{} {}{} ago", num, label, if num == 1 { "" } else { "s" })
+// checkin-embedded-client --sign-release <binary> <version> [release key file]
+fn sign_release() {
+    let args: Vec<String> = std::env::args().skip_while(|arg| arg != "--sign-release").skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("Usage: checkin-embedded-client --sign-release <binary> <version> [release key file]");
+        std::process::exit(1);
     }
+    let key_file = args.get(2).map(String::as_str).unwrap_or("./release.key");
+    match releases::sign_release(&args[0], &args[1], key_file) {
+        Ok(release_key) => {
+            println!("Wrote releases/{}, copy it into the manager's RELEASES_DIR", &args[1]);
+            println!("Devices need release_key = \"{}\"", release_key);
+        },
+        Err(err) => {
+            eprintln!("Could not sign release: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let weeks = duration.num_weeks();
-    if weeks > 0 {
-        return pluralizer(weeks, "week");
+fn start_notifier(config: &Config, simulate: bool) -> Notifier {
+    if simulate {
+        simulator::notifier()
     }
-    let days = duration.num_days();
-    if days > 0 {
-        return pluralizer(days, "day");
+    else {
+        Notifier::start(config.success_display_address, config.error_display_address, config.buzzer_pin)
     }
-    let hours = duration.num_hours();
-    if hours > 0 {
-        return pluralizer(hours, "hour");
+}
+
+// Falls back to the default hardware layout to tell whoever is setting up the kiosk what to fix
+fn show_config_errors(errors: &[String], simulate: bool) -> ! {
+    for error in errors {
+        eprintln!("Invalid configuration: {}", error);
     }
-    let minutes = duration.num_minutes();
-    if minutes > 0 {
-        return pluralizer(minutes, "minute");
+    let defaults = Config::default();
+    let notifier = start_notifier(&defaults, simulate);
+    if !simulate {
+        notifier.setup_reset_button(defaults.reset_button_pin);
+    }
+    loop {
+        notifier.scroll_text_speed("Invalid config", 30);
+        for error in errors {
+            notifier.scroll_text_speed(error, 10);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(30));
     }
-    pluralizer(duration.num_seconds(), "second")
 }
+
@@ -1,11 +1,46 @@
 use rppal::i2c::I2c;
-use rppal::i2c::Result;
-use rppal::gpio::Gpio;
+use rppal::gpio::{ Gpio, OutputPin };
 use std::{ thread, time };
-use std::sync::{ Arc, Mutex };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use serde::Deserialize;
 use crate::api::ManagerAPI;
 
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// An 8x8 LED matrix
+pub trait Display: Send {
+	fn set_pixel(&mut self, x: u8, y: u8, on: bool);
+	/// Pushes pixels set since the last call out to the display
+	fn display_buffer(&mut self) -> Result<()>;
+	fn set_brightness(&self, level: u8) -> Result<()>;
+
+	fn all_on(&mut self) -> Result<()> {
+		for x in 0..8 {
+			for y in 0..8 {
+				self.set_pixel(x, y, true);
+			}
+		}
+		self.display_buffer()?;
+		Ok(())
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		for x in 0..8 {
+			for y in 0..8 {
+				self.set_pixel(x, y, false);
+			}
+		}
+		self.display_buffer()?;
+		Ok(())
+	}
+}
+
+/// Plays tones, blocking until each one is done
+pub trait Buzzer: Send {
+	fn play(&mut self, tone: &Tone);
+}
+
 pub struct HT16K33 {
 	device: I2c,
 	buffer: [u8; 16],
@@ -147,77 +182,17 @@ impl HT16K33 {
 		HT16K33::clear(&mut instance)?;
 		Ok(instance)
 	}
+}
 
-	pub fn scroll_text(text: &str, devices: &mut [&mut HT16K33], millis_per_column: u64) -> Result<()> {
-		let mut columns: Vec<u8> = Vec::new();
-
-		for character in text.chars() {
-			let c = Char::from(character);
-
-			for x in ((5 - c.0)..5).rev() {
-				let row = ((c.1 >> x * 8) & 0xFF) as u8;
-
-				columns.push(row);
-			}
-			if character != ' ' {
-				// Add a blank column between characters
-				columns.push(0x0);
-			}
-		}
-
-		// From 0 to text length + screen length (text slides entirely past)
-		for column_number in 0..columns.len() + 8 * devices.len() {
-			let mut i = column_number as i32; // First column's (AKA start of string) location
-			for column in columns.iter() {
-				// If this piece of text is on-screen
-				if i < 8 * devices.len() as i32 {
-					for y in 0..8 {
-						let device = (i / 8) as usize; // 8 columns per device
-						let x = i % 8;
-						let on = column & (1 << (7 - y)) != 0;
-						devices[device].set_pixel(x as u8, y, on);
-					}
-				}
-				i -= 1;
-				if i < 0 {
-					break;
-				}
-			}
-			let after_text_column = column_number as i32 - columns.len() as i32;
-			if after_text_column >= 0 {
-				// Clear column after text has scrolled by
-				for y in 0..8 {
-					let device = (after_text_column / 8) as usize; // 8 columns per device
-					let x = after_text_column % 8;
-					devices[device].set_pixel(x as u8, y, false);
-				}
-			}
-			for device in devices.iter_mut() {
-				device.display_buffer()?;
-			}
-			thread::sleep(time::Duration::from_millis(millis_per_column));
-		}
-		Ok(())
-	}
-
-	pub fn all_on(&mut self) -> Result<()> {
-		for x in 0..8 {
-			for y in 0..8 {
-				self.set_pixel(x, y, true);
-			}
-		}
-		self.display_buffer()?;
-		Ok(())
-	}
-
-	pub fn set_brightness(&self, level: u8) -> Result<()> {
+impl Display for HT16K33 {
+	fn set_brightness(&self, level: u8) -> Result<()> {
 		if level > 15 {
 			panic!("Brightness must be a value of 0 to 15");
 		}
-		self.device.block_write(HT16K33_CMD_BRIGHTNESS | level, &[])
+		Ok(self.device.block_write(HT16K33_CMD_BRIGHTNESS | level, &[])?)
 	}
 
-	pub fn set_pixel(&mut self, x: u8, y: u8, on: bool) {
+	fn set_pixel(&mut self, x: u8, y: u8, on: bool) {
 		if x > 7 || y > 7 {
 			panic!("Pixel location out of range ({}, {})", x, y);
 		}
@@ -232,23 +207,77 @@ impl HT16K33 {
 		}
 	}
 
-	pub fn display_buffer(&mut self) -> Result<()> {
+	fn display_buffer(&mut self) -> Result<()> {
 		for i in 0..self.buffer.len() {
 			let value = self.buffer[i];
 			self.device.write(&[i as u8, value])?;
 		}
 		Ok(())
 	}
+}
 
-	pub fn clear(&mut self) -> Result<()> {
-		for x in 0..8 {
+/// Starts this program over with the same arguments, as if the device had been power cycled. Only returns if that fails.
+pub fn restart() -> std::io::Error {
+	exec_binary(std::path::Path::new("/proc/self/exe"))
+}
+
+/// Replaces this process with `path`, passing along our arguments. Only returns if that fails.
+pub fn exec_binary(path: &std::path::Path) -> std::io::Error {
+	use std::os::unix::process::CommandExt;
+	std::process::Command::new(path).args(std::env::args_os().skip(1)).exec()
+}
+
+/// Scrolls text across a row of displays. List goes right to left.
+pub fn scroll_text(text: &str, devices: &mut [&mut dyn Display], millis_per_column: u64) -> Result<()> {
+	let mut columns: Vec<u8> = Vec::new();
+
+	for character in text.chars() {
+		let c = Char::from(character);
+
+		for x in ((5 - c.0)..5).rev() {
+			let row = ((c.1 >> x * 8) & 0xFF) as u8;
+
+			columns.push(row);
+		}
+		if character != ' ' {
+			// Add a blank column between characters
+			columns.push(0x0);
+		}
+	}
+
+	// From 0 to text length + screen length (text slides entirely past)
+	for column_number in 0..columns.len() + 8 * devices.len() {
+		let mut i = column_number as i32; // First column's (AKA start of string) location
+		for column in columns.iter() {
+			// If this piece of text is on-screen
+			if i < 8 * devices.len() as i32 {
+				for y in 0..8 {
+					let device = (i / 8) as usize; // 8 columns per device
+					let x = i % 8;
+					let on = column & (1 << (7 - y)) != 0;
+					devices[device].set_pixel(x as u8, y, on);
+				}
+			}
+			i -= 1;
+			if i < 0 {
+				break;
+			}
+		}
+		let after_text_column = column_number as i32 - columns.len() as i32;
+		if after_text_column >= 0 {
+			// Clear column after text has scrolled by
 			for y in 0..8 {
-				self.set_pixel(x, y, false);
+				let device = (after_text_column / 8) as usize; // 8 columns per device
+				let x = after_text_column % 8;
+				devices[device].set_pixel(x as u8, y, false);
 			}
 		}
-		self.display_buffer()?;
-		Ok(())
+		for device in devices.iter_mut() {
+			device.display_buffer()?;
+		}
+		thread::sleep(time::Duration::from_millis(millis_per_column));
 	}
+	Ok(())
 }
 
 pub struct Tone {
@@ -259,33 +288,152 @@ impl Tone {
 	pub fn new(frequency: f64, millis: u64) -> Self {
 		Self { frequency, duration: time::Duration::from_millis(millis) }
 	}
+
+	pub fn frequency(&self) -> f64 {
+		self.frequency
+	}
+
+	pub fn duration(&self) -> time::Duration {
+		self.duration
+	}
+}
+
+/// Set of tones chosen in the manager
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ToneTheme {
+	Classic,
+	/// An octave higher
+	High,
+	/// An octave lower
+	Low,
+}
+impl ToneTheme {
+	fn apply(&self, tone: &Tone) -> Tone {
+		let multiplier = match self {
+			ToneTheme::Classic => 1.0,
+			ToneTheme::High => 2.0,
+			ToneTheme::Low => 0.5,
+		};
+		Tone { frequency: tone.frequency * multiplier, duration: tone.duration }
+	}
+}
+
+/// Piezo buzzer driven by toggling a GPIO pin
+pub struct GPIOBuzzer {
+	pin: OutputPin,
+}
+impl GPIOBuzzer {
+	pub fn new(pin: u8) -> Self {
+		let gpio = Gpio::new().unwrap();
+		Self { pin: gpio.get(pin).unwrap().into_output() }
+	}
+}
+impl Buzzer for GPIOBuzzer {
+	fn play(&mut self, tone: &Tone) {
+		let start = time::Instant::now();
+		if tone.frequency == 0.0 {
+			thread::sleep(tone.duration);
+		}
+		else {
+			let microseconds = (1.0 / tone.frequency) * 1000.0 * 1000.0;
+
+			while start.elapsed() < tone.duration {
+				self.pin.set_high();
+				thread::sleep(time::Duration::from_micros(microseconds as u64));
+				self.pin.set_low();
+				thread::sleep(time::Duration::from_micros(microseconds as u64));
+			}
+		}
+	}
 }
 
 /// Spawns threads to control peripherals without blocking the main thread
 pub struct Notifier {
-	success_display: Arc<Mutex<HT16K33>>,
-	error_display: Arc<Mutex<HT16K33>>,
+	success_display: Arc<Mutex<Box<dyn Display>>>,
+	error_display: Arc<Mutex<Box<dyn Display>>>,
 	display_lock: Arc<AtomicBool>,
-	buzzer: Arc<Mutex<rppal::gpio::OutputPin>>,
+	buzzer: Arc<Mutex<Box<dyn Buzzer>>>,
+	// Settings pushed from the manager
+	scroll_speed: AtomicU64,
+	buzzer_enabled: AtomicBool,
+	tone_theme: RwLock<ToneTheme>,
+	idle_message: RwLock<Option<String>>,
+	last_active: Mutex<time::Instant>,
 }
 impl Notifier {
+	/// Uses the HT16K33 displays and GPIO buzzer on the Raspberry Pi
 	pub fn start(success_display_address: u8, error_display_address: u8, buzzer_pin: u8) -> Self {
-		let success_display = Arc::new(Mutex::new(HT16K33::new(success_display_address).unwrap()));
-        let error_display = Arc::new(Mutex::new(HT16K33::new(error_display_address).unwrap()));
+		let success_display = HT16K33::new(success_display_address).unwrap();
+        let error_display = HT16K33::new(error_display_address).unwrap();
+		let buzzer = GPIOBuzzer::new(buzzer_pin);
 
-		let gpio = Gpio::new().unwrap();
-		let buzzer = gpio.get(buzzer_pin).unwrap().into_output();
-		let buzzer = Arc::new(Mutex::new(buzzer));
+		Notifier::new(Box::new(success_display), Box::new(error_display), Box::new(buzzer))
+	}
 
+	pub fn new(success_display: Box<dyn Display>, error_display: Box<dyn Display>, buzzer: Box<dyn Buzzer>) -> Self {
 		Self {
-			success_display,
-			error_display,
+			success_display: Arc::new(Mutex::new(success_display)),
+			error_display: Arc::new(Mutex::new(error_display)),
 			display_lock: Arc::new(AtomicBool::new(false)), // Is a display locked?
-			buzzer,
+			buzzer: Arc::new(Mutex::new(buzzer)),
+			scroll_speed: AtomicU64::new(5), // A speedy yet readable default
+			buzzer_enabled: AtomicBool::new(true),
+			tone_theme: RwLock::new(ToneTheme::Classic),
+			idle_message: RwLock::new(None),
+			last_active: Mutex::new(time::Instant::now()),
+		}
+	}
+
+	pub const MAX_BRIGHTNESS: u8 = 15;
+
+	/// 0 (dimmest) to 15, higher levels are treated as 15
+	pub fn set_brightness(&self, level: u8) {
+		let level = level.min(Notifier::MAX_BRIGHTNESS);
+		for display in [&self.success_display, &self.error_display].iter() {
+			if let Err(err) = display.lock().unwrap().set_brightness(level) {
+				eprintln!("Error setting brightness: {:?}", err);
+			}
 		}
 	}
 
+	pub fn set_scroll_speed(&self, millis_per_column: u64) {
+		self.scroll_speed.store(millis_per_column, Ordering::SeqCst);
+	}
+
+	pub fn set_buzzer_enabled(&self, enabled: bool) {
+		self.buzzer_enabled.store(enabled, Ordering::SeqCst);
+	}
+
+	pub fn set_tone_theme(&self, theme: ToneTheme) {
+		*self.tone_theme.write().unwrap() = theme;
+	}
+
+	pub fn set_idle_message(&self, message: Option<String>) {
+		*self.idle_message.write().unwrap() = message;
+	}
+
+	/// Spawns a thread that scrolls the idle message whenever the displays have been unused for `seconds`
+	pub fn start_idle_messages(notifier: &Arc<Notifier>, seconds: u64) {
+		let notifier = Arc::clone(notifier);
+		thread::spawn(move || {
+			loop {
+				thread::sleep(time::Duration::from_secs(1));
+				let idle = notifier.last_active.lock().unwrap().elapsed() >= time::Duration::from_secs(seconds);
+				if !idle {
+					continue;
+				}
+				let message = notifier.idle_message.read().unwrap().clone();
+				match message {
+					Some(message) => notifier.scroll_text(&message),
+					// Check again in another full period if a message gets set
+					None => *notifier.last_active.lock().unwrap() = time::Instant::now(),
+				}
+			}
+		});
+	}
+
 	fn wait_for_display_lock(&self) -> Arc<AtomicBool> {
+		*self.last_active.lock().unwrap() = time::Instant::now();
 		let display_lock = Arc::clone(&self.display_lock);
 		// Wait until a previous notifier thread acquires its individual display locks and unlocks the general display_lock
 		while display_lock.compare_and_swap(false, true, Ordering::Acquire) {
@@ -296,8 +444,7 @@ impl Notifier {
 	}
 
 	pub fn scroll_text(&self, text: &str) {
-		const SPEED: u64 = 5; // A speedy yet readable default
-		self.scroll_text_speed(text, SPEED);
+		self.scroll_text_speed(text, self.scroll_speed.load(Ordering::SeqCst));
 	}
 
 	pub fn scroll_text_speed(&self, text: &str, millis_per_column: u64) {
@@ -312,7 +459,7 @@ impl Notifier {
 			display_lock.store(false, Ordering::Release);
 
 			// List goes right to left
-			HT16K33::scroll_text(&text, &mut [&mut *error_display, &mut *success_display], millis_per_column).unwrap();
+			scroll_text(&text, &mut [&mut **error_display, &mut **success_display], millis_per_column).unwrap();
 		});
 	}
 
@@ -365,40 +512,29 @@ impl Notifier {
 	}
 
 	pub fn beep(&self, tones: Vec<Tone>) {
+		if !self.buzzer_enabled.load(Ordering::SeqCst) {
+			return;
+		}
+		let theme = *self.tone_theme.read().unwrap();
+		let tones: Vec<Tone> = tones.iter().map(|tone| theme.apply(tone)).collect();
 		let buzzer = Arc::clone(&self.buzzer);
 		thread::spawn(move || {
 			let mut buzzer = buzzer.lock().unwrap();
 
 			for tone in tones.iter() {
-				let start = time::Instant::now();
-				if tone.frequency == 0.0 {
-					thread::sleep(tone.duration);
-				}
-				else {
-					let microseconds = (1.0 / tone.frequency) * 1000.0 * 1000.0;
-
-					while start.elapsed() < tone.duration {
-						buzzer.set_high();
-						thread::sleep(time::Duration::from_micros(microseconds as u64));
-						buzzer.set_low();
-						thread::sleep(time::Duration::from_micros(microseconds as u64));
-					}
-				}
+				buzzer.play(tone);
 			}
 		});
 	}
 
-	pub fn setup_reset_button(&self) {
-		const RESET_BUTTON: u8 = 24;
-
+	pub fn setup_reset_button(&self, pin: u8) {
 		let gpio = Gpio::new().unwrap();
-		let button = gpio.get(RESET_BUTTON).unwrap().into_input_pullup();
+		let button = gpio.get(pin).unwrap().into_input_pullup();
 
 		thread::spawn(move || {
 			loop {
 				if button.is_low() {
-					use std::os::unix::process::CommandExt;
-					std::process::Command::new("/proc/self/exe").exec();
+					restart();
 					while button.is_low() {
 						thread::sleep(time::Duration::from_millis(50));
 					}
@@ -408,13 +544,11 @@ impl Notifier {
 		});
 	}
 
-	pub fn setup_tag_button(&self, manager: &Arc<ManagerAPI>, notifier: &Arc<Notifier>) {
-		const TAG_BUTTON: u8 = 23;
-
+	pub fn setup_tag_button(&self, pin: u8, manager: &Arc<ManagerAPI>, notifier: &Arc<Notifier>) {
 		let manager = Arc::clone(manager);
 		let notifier = Arc::clone(notifier);
 		let gpio = Gpio::new().unwrap();
-		let button = gpio.get(TAG_BUTTON).unwrap().into_input_pullup();
+		let button = gpio.get(pin).unwrap().into_input_pullup();
 
 		thread::spawn(move || {
 			loop {
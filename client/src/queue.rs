@@ -0,0 +1,232 @@
+use std::fs;
+use std::io::{ self, BufRead, Write };
+use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+use std::{ thread, time };
+use serde::{ Serialize, Deserialize };
+use hackgt_nfc::api::CheckinAPI;
+use crate::peripherals::{ self, Notifier };
+
+/// A check-in that couldn't reach the check-in API when the badge was tapped
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingCheckIn {
+	pub badge_id: String,
+	pub tag: String,
+	pub timestamp: String,
+	pub device: String,
+}
+
+/// On-disk journal of check-ins waiting to be sent. Stored as one JSON object per line so it survives reboots.
+pub struct CheckInQueue {
+	path: PathBuf,
+	pending: Mutex<VecDeque<PendingCheckIn>>,
+}
+
+impl CheckInQueue {
+	pub fn load(path: &str) -> Self {
+		let path = PathBuf::from(path);
+		let mut pending = VecDeque::new();
+		let mut skipped = false;
+		match fs::File::open(&path) {
+			Ok(file) => {
+				for line in io::BufReader::new(file).lines() {
+					let line = match line {
+						Ok(line) => line,
+						Err(err) => {
+							eprintln!("Error reading check-in journal: {:?}", err);
+							break;
+						}
+					};
+					// A partially written last line from a power cut will fail to parse so just skip it
+					match serde_json::from_str(&line) {
+						Ok(checkin) => pending.push_back(checkin),
+						Err(_) => {
+							eprintln!("Skipping corrupt check-in journal entry: {}", line);
+							skipped = true;
+						},
+					}
+				}
+			},
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+			Err(err) => panic!("There was an error opening the check-in journal: {:?}", err),
+		}
+		if !pending.is_empty() {
+			println!("Loaded {} queued check-ins", pending.len());
+		}
+		let queue = Self {
+			path,
+			pending: Mutex::new(pending),
+		};
+		// Otherwise the next push would be appended onto the end of a partial line and get lost with it
+		if skipped {
+			if let Err(err) = queue.rewrite(&queue.pending.lock().unwrap()) {
+				eprintln!("Error rewriting check-in journal: {:?}", err);
+			}
+		}
+		queue
+	}
+
+	pub fn len(&self) -> usize {
+		self.pending.lock().unwrap().len()
+	}
+
+	pub fn push(&self, checkin: PendingCheckIn) -> io::Result<()> {
+		let mut pending = self.pending.lock().unwrap();
+		let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+		let line = serde_json::to_string(&checkin).expect("Could not serialize check-in");
+		writeln!(file, "{}", line)?;
+		file.sync_data()?;
+		pending.push_back(checkin);
+		Ok(())
+	}
+
+	// Writes out a new journal and atomically swaps it in so that a crash never loses entries
+	fn rewrite(&self, pending: &VecDeque<PendingCheckIn>) -> io::Result<()> {
+		let temp_path = self.path.with_extension("tmp");
+		{
+			let mut file = fs::File::create(&temp_path)?;
+			for checkin in pending.iter() {
+				writeln!(file, "{}", serde_json::to_string(checkin).expect("Could not serialize check-in"))?;
+			}
+			file.sync_all()?;
+		}
+		fs::rename(&temp_path, &self.path)
+	}
+
+	fn pop_front(&self) {
+		let mut pending = self.pending.lock().unwrap();
+		pending.pop_front();
+		if let Err(err) = self.rewrite(&pending) {
+			eprintln!("Error rewriting check-in journal: {:?}", err);
+		}
+	}
+
+	/// Spawns a thread that sends queued check-ins in order once the check-in API is reachable again
+	pub fn start_replaying(queue: &Arc<CheckInQueue>, api: &Arc<CheckinAPI>, notifier: &Arc<Notifier>) {
+		let queue = Arc::clone(queue);
+		let api = Arc::clone(api);
+		let notifier = Arc::clone(notifier);
+		thread::spawn(move || {
+			loop {
+				queue.replay(&api, &notifier);
+				thread::sleep(time::Duration::from_secs(15));
+			}
+		});
+	}
+
+	fn replay(&self, api: &CheckinAPI, notifier: &Notifier) {
+		loop {
+			let checkin = match self.pending.lock().unwrap().front() {
+				Some(checkin) => checkin.clone(),
+				None => return,
+			};
+			match api.check_in(&checkin.badge_id, &checkin.tag) {
+				Ok((true, user, _)) => {
+					println!("Checked in {} from queue (tapped at {})", &user.name, &checkin.timestamp);
+				},
+				Ok((false, user, _)) => {
+					println!("Queued check-in for {} at {} conflicted: already checked in to {}", &user.name, &checkin.timestamp, &checkin.tag);
+					notifier.beep(vec![
+						peripherals::Tone::new(261.63, 200),
+					]);
+					notifier.scroll_text_speed(&format!("Queued: {} already checked in", &user.name), 10);
+				},
+				// Errors with a message come from the API itself so retrying won't help
+				Err(hackgt_nfc::api::Error::Message(message)) => {
+					println!("Dropping queued check-in for badge {} at {}: {}", &checkin.badge_id, &checkin.timestamp, message);
+					notifier.scroll_text_speed(&format!("Queued check-in failed: {}", message), 10);
+				},
+				Err(err) => {
+					// Probably still offline so try again later
+					println!("Replaying check-in queue: {:?}", err);
+					return;
+				},
+			}
+			self.pop_front();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_path(name: &str) -> String {
+		let path: PathBuf = std::env::temp_dir().join(format!("checkin-queue-{}-{}", std::process::id(), name));
+		let _ = fs::remove_file(&path);
+		path.to_str().unwrap().to_owned()
+	}
+
+	fn checkin(badge_id: &str) -> PendingCheckIn {
+		PendingCheckIn {
+			badge_id: badge_id.to_owned(),
+			tag: "lunch".to_owned(),
+			timestamp: "2019-10-25T12:00:00+00:00".to_owned(),
+			device: "test".to_owned(),
+		}
+	}
+
+	fn badge_ids(queue: &CheckInQueue) -> Vec<String> {
+		queue.pending.lock().unwrap().iter().map(|checkin| checkin.badge_id.clone()).collect()
+	}
+
+	#[test]
+	fn starts_empty_without_a_journal() {
+		let queue = CheckInQueue::load(&temp_path("missing"));
+		assert_eq!(queue.len(), 0);
+	}
+
+	#[test]
+	fn pushed_check_ins_survive_reloading() {
+		let path = temp_path("push");
+		let queue = CheckInQueue::load(&path);
+		queue.push(checkin("a")).unwrap();
+		queue.push(checkin("b")).unwrap();
+		assert_eq!(queue.len(), 2);
+
+		let reloaded = CheckInQueue::load(&path);
+		assert_eq!(badge_ids(&reloaded), vec!["a", "b"]);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn sent_check_ins_are_removed_from_the_journal() {
+		let path = temp_path("rewrite");
+		let queue = CheckInQueue::load(&path);
+		queue.push(checkin("a")).unwrap();
+		queue.push(checkin("b")).unwrap();
+		queue.pop_front();
+		assert_eq!(badge_ids(&queue), vec!["b"]);
+		assert!(!PathBuf::from(&path).with_extension("tmp").exists());
+
+		let reloaded = CheckInQueue::load(&path);
+		assert_eq!(badge_ids(&reloaded), vec!["b"]);
+		queue.pop_front();
+		assert_eq!(CheckInQueue::load(&path).len(), 0);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn skips_corrupt_journal_lines() {
+		let path = temp_path("corrupt");
+		let queue = CheckInQueue::load(&path);
+		queue.push(checkin("a")).unwrap();
+		{
+			let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+			writeln!(file, "not json").unwrap();
+		}
+		queue.push(checkin("b")).unwrap();
+		// What a power cut in the middle of a write leaves behind
+		{
+			let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+			write!(file, "{{\"badge_id\": \"c\", \"ta").unwrap();
+		}
+
+		let reloaded = CheckInQueue::load(&path);
+		assert_eq!(badge_ids(&reloaded), vec!["a", "b"]);
+		reloaded.push(checkin("d")).unwrap();
+		assert_eq!(badge_ids(&CheckInQueue::load(&path)), vec!["a", "b", "d"]);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,131 @@
+use std::{ thread, time };
+use std::sync::{ Arc, Mutex };
+use crate::peripherals::{ Display, Buzzer, Notifier, Tone, Result };
+
+/// Something a recording peripheral was asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+	// Pixels are stored row by row with (0, 0) as the lowest bit
+	Frame { display: &'static str, pixels: u64 },
+	Brightness { display: &'static str, level: u8 },
+	Tone { frequency: f64, millis: u64 },
+}
+
+/// In-memory peripherals that log everything they're told to do so notification sequences can be checked without a Raspberry Pi
+#[derive(Clone)]
+pub struct Recording {
+	events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Recording {
+	pub const ALL_ON: u64 = std::u64::MAX;
+	pub const ALL_OFF: u64 = 0;
+
+	pub fn new() -> Self {
+		Self {
+			events: Arc::new(Mutex::new(Vec::new())),
+		}
+	}
+
+	/// A notifier with "success" and "error" displays and a buzzer that all record into this log
+	pub fn notifier(&self) -> Notifier {
+		Notifier::new(Box::new(self.display("success")), Box::new(self.display("error")), Box::new(self.buzzer()))
+	}
+
+	pub fn display(&self, name: &'static str) -> RecordingDisplay {
+		RecordingDisplay {
+			name,
+			pixels: 0,
+			events: Arc::clone(&self.events),
+		}
+	}
+
+	pub fn buzzer(&self) -> RecordingBuzzer {
+		RecordingBuzzer {
+			events: Arc::clone(&self.events),
+		}
+	}
+
+	pub fn events(&self) -> Vec<Event> {
+		self.events.lock().unwrap().clone()
+	}
+
+	/// Frames pushed to a single display, in order
+	pub fn frames(&self, display: &str) -> Vec<u64> {
+		self.events().into_iter().filter_map(|event| match event {
+			Event::Frame { display: name, pixels } if name == display => Some(pixels),
+			_ => None,
+		}).collect()
+	}
+
+	pub fn tones(&self) -> Vec<(f64, u64)> {
+		self.events().into_iter().filter_map(|event| match event {
+			Event::Tone { frequency, millis } => Some((frequency, millis)),
+			_ => None,
+		}).collect()
+	}
+
+	/// Notifier work happens on background threads so wait for it to settle before checking what was recorded
+	pub fn wait_until_idle(&self, timeout: time::Duration) {
+		// Longer than any single flash or pause in our sequences
+		const QUIET_PERIOD: time::Duration = time::Duration::from_millis(1000);
+		let start = time::Instant::now();
+		let mut last_count = self.events.lock().unwrap().len();
+		let mut last_change = time::Instant::now();
+		while start.elapsed() < timeout {
+			thread::sleep(time::Duration::from_millis(10));
+			let count = self.events.lock().unwrap().len();
+			if count != last_count {
+				last_count = count;
+				last_change = time::Instant::now();
+			}
+			else if last_change.elapsed() > QUIET_PERIOD {
+				return;
+			}
+		}
+		panic!("Peripherals were still busy after {:?}", timeout);
+	}
+}
+
+pub struct RecordingDisplay {
+	name: &'static str,
+	pixels: u64,
+	events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Display for RecordingDisplay {
+	fn set_pixel(&mut self, x: u8, y: u8, on: bool) {
+		if x > 7 || y > 7 {
+			panic!("Pixel location out of range ({}, {})", x, y);
+		}
+		let bit = 1u64 << (y * 8 + x);
+		if on {
+			self.pixels |= bit;
+		}
+		else {
+			self.pixels &= !bit;
+		}
+	}
+
+	fn display_buffer(&mut self) -> Result<()> {
+		self.events.lock().unwrap().push(Event::Frame { display: self.name, pixels: self.pixels });
+		Ok(())
+	}
+
+	fn set_brightness(&self, level: u8) -> Result<()> {
+		self.events.lock().unwrap().push(Event::Brightness { display: self.name, level });
+		Ok(())
+	}
+}
+
+pub struct RecordingBuzzer {
+	events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Buzzer for RecordingBuzzer {
+	// Doesn't wait out the tone so tests stay fast
+	fn play(&mut self, tone: &Tone) {
+		let millis = tone.duration().as_millis() as u64;
+		self.events.lock().unwrap().push(Event::Tone { frequency: tone.frequency(), millis });
+	}
+}
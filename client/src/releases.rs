@@ -0,0 +1,414 @@
+use std::{ fs, io, thread, time };
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ self, AtomicBool };
+use serde::{ Serialize, Deserialize };
+use ed25519_dalek::{ PublicKey, Signature };
+use crate::api::ManagerAPI;
+use crate::crypto::Signer;
+use crate::keyfile;
+use crate::peripherals;
+
+const BINARY_NAME: &'static str = "checkin-embedded-client";
+const CURRENT_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+// Written before switching to a new build and removed once it has started up properly
+const PENDING_FILE: &'static str = "update.pending";
+// Versions that were rolled back so that we don't keep installing them
+const FAILED_FILE: &'static str = "update.failed";
+
+/// A client build offered by the manager. Signed offline with the release key so that the manager can't push its own builds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Release {
+	pub version: String,
+	/// Hex SHA-256 of the binary
+	pub sha256: String,
+	/// Hex ed25519 signature over `signed_message(version, sha256)`
+	pub signature: String,
+}
+
+// The version is signed along with the hash so that an old build can't be passed off as a newer one
+fn signed_message(version: &str, sha256: &str) -> String {
+	format!("{} {} {}", BINARY_NAME, version, sha256)
+}
+
+impl Release {
+	/// Checks that `binary` is this release and that the release was signed with `release_key`
+	pub fn verify(&self, binary: &[u8], release_key: &PublicKey) -> Result<(), &'static str> {
+		if crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, binary) != self.sha256 {
+			return Err("Downloaded binary doesn't match the release hash");
+		}
+		let signature = hex::decode(&self.signature).ok()
+			.and_then(|signature| Signature::from_bytes(&signature).ok())
+			.ok_or("Invalid release signature")?;
+		release_key.verify(signed_message(&self.version, &self.sha256).as_bytes(), &signature)
+			.map_err(|_| "Release wasn't signed with the release key")
+	}
+}
+
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+	version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Compares dotted version numbers like 0.1.10 and 0.2.0. Returns None if either isn't one.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+	Some(parse_version(a)?.cmp(&parse_version(b)?))
+}
+
+/// Signs a build for release with the key in `key_file` (generated if it doesn't exist) and writes it to
+/// `./releases/<version>/` in the layout the manager serves. Returns the release key to pin on devices.
+/// The release key stays on the build machine so it isn't encrypted, just kept readable only by its owner.
+pub fn sign_release(binary_path: &str, version: &str, key_file: &str) -> io::Result<String> {
+	if parse_version(version).is_none() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Version must be dotted numbers like 0.2.0"));
+	}
+	let key = Signer::load(key_file, None).map_err(|err| match err {
+		keyfile::Error::IO(err) => err,
+		err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+	})?;
+	let binary = fs::read(binary_path)?;
+	let sha256 = crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &binary);
+	let signature = key.sign(signed_message(version, &sha256).as_bytes());
+	let release = Release {
+		version: version.to_owned(),
+		sha256,
+		signature: hex::encode(&signature.to_bytes()[..]),
+	};
+
+	let dir = Path::new("./releases").join(version);
+	fs::create_dir_all(&dir)?;
+	fs::write(dir.join(BINARY_NAME), &binary)?;
+	fs::write(dir.join("release.json"), serde_json::to_string_pretty(&release).expect("Could not serialize release"))?;
+	Ok(hex::encode(&key.get_public_key()[..]))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct PendingUpdate {
+	version: String,
+	/// Seconds since the UNIX epoch. Decided by the build that installed the update so that it doesn't depend on
+	/// the new build being able to read its config.
+	roll_back_at: u64,
+}
+
+fn unix_time() -> u64 {
+	time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[derive(Debug, PartialEq)]
+enum WatchdogCheck {
+	/// Not trying out a new build
+	Idle,
+	/// A new build already ran out of time, e.g. it keeps crashing on startup
+	RollBack,
+	/// Give the new build until this time to call `started`
+	Deadline(u64),
+}
+
+/// Rolls back builds that don't start up properly
+pub struct Watchdog {
+	// Resolved at startup since /proc/self/exe keeps pointing at the old build once the new one is swapped in
+	exe: PathBuf,
+	pending_file: PathBuf,
+	failed_file: PathBuf,
+	started: AtomicBool,
+}
+
+impl Watchdog {
+	/// Should be called before anything that could hang or crash, including reading the config
+	pub fn start() -> Arc<Watchdog> {
+		let watchdog = Arc::new(Watchdog::new(std::env::current_exe().expect("Could not find our own binary"), Path::new(".")));
+		match watchdog.check(unix_time()) {
+			WatchdogCheck::Idle => {},
+			WatchdogCheck::RollBack => watchdog.roll_back(),
+			WatchdogCheck::Deadline(deadline) => {
+				println!("Running new version {}, rolling back if we can't start up in time", CURRENT_VERSION);
+				let watchdog = Arc::clone(&watchdog);
+				thread::spawn(move || {
+					while unix_time() < deadline {
+						if watchdog.started.load(atomic::Ordering::SeqCst) {
+							return;
+						}
+						thread::sleep(time::Duration::from_secs(5));
+					}
+					if !watchdog.started.load(atomic::Ordering::SeqCst) {
+						watchdog.roll_back();
+					}
+				});
+			},
+		}
+		watchdog
+	}
+
+	/// `dir` holds the files that track updates
+	fn new(exe: PathBuf, dir: &Path) -> Self {
+		Self {
+			exe,
+			pending_file: dir.join(PENDING_FILE),
+			failed_file: dir.join(FAILED_FILE),
+			started: AtomicBool::new(false),
+		}
+	}
+
+	fn backup_path(&self) -> PathBuf {
+		self.exe.with_extension("previous")
+	}
+
+	fn pending_update(&self) -> Option<PendingUpdate> {
+		let file = fs::read_to_string(&self.pending_file).ok()?;
+		serde_json::from_str(&file).ok()
+	}
+
+	fn failed_versions(&self) -> HashSet<String> {
+		fs::read_to_string(&self.failed_file).unwrap_or_default()
+			.lines()
+			.map(str::to_owned)
+			.collect()
+	}
+
+	fn check(&self, now: u64) -> WatchdogCheck {
+		let pending = match self.pending_update() {
+			Some(pending) => pending,
+			None => return WatchdogCheck::Idle,
+		};
+		if pending.version != CURRENT_VERSION {
+			// We're the build that was being replaced so the swap never happened or was already undone
+			let _ = fs::remove_file(&self.pending_file);
+			return WatchdogCheck::Idle;
+		}
+		if now >= pending.roll_back_at {
+			WatchdogCheck::RollBack
+		}
+		else {
+			WatchdogCheck::Deadline(pending.roll_back_at)
+		}
+	}
+
+	/// Called once we've reached the manager and are ready for badge taps. Keeps the new build if we were trying one out.
+	pub fn started(&self) {
+		self.started.store(true, atomic::Ordering::SeqCst);
+		if self.pending_update().map(|pending| pending.version == CURRENT_VERSION).unwrap_or(false) {
+			println!("Update to version {} succeeded", CURRENT_VERSION);
+			let _ = fs::remove_file(&self.pending_file);
+			let _ = fs::remove_file(self.backup_path());
+		}
+	}
+
+	fn roll_back(&self) {
+		eprintln!("Version {} didn't start up in time, rolling back", CURRENT_VERSION);
+		if let Err(err) = self.restore_previous() {
+			eprintln!("Rollback failed: {:?}", err);
+			return;
+		}
+		let err = peripherals::exec_binary(&self.exe);
+		eprintln!("Could not start previous version: {:?}", err);
+	}
+
+	/// Puts the previous build back and remembers not to install this one again
+	fn restore_previous(&self) -> io::Result<()> {
+		let mut failed = fs::OpenOptions::new().create(true).append(true).open(&self.failed_file);
+		if let Ok(file) = failed.as_mut() {
+			let _ = writeln!(file, "{}", CURRENT_VERSION);
+		}
+		fs::rename(self.backup_path(), &self.exe)?;
+		let _ = fs::remove_file(&self.pending_file);
+		Ok(())
+	}
+
+	/// Replaces our binary with `binary`, keeping the current one to roll back to
+	fn swap_in(&self, binary: &[u8], version: &str, rollback_after: time::Duration) -> io::Result<()> {
+		// Trying out a new build that hasn't proven itself yet would overwrite the only good backup
+		if self.pending_update().is_some() {
+			return Err(io::Error::new(io::ErrorKind::Other, "Another update is still pending"));
+		}
+		let swap = || -> io::Result<()> {
+			fs::copy(&self.exe, self.backup_path())?;
+			// Written next to the running binary so that the rename is atomic
+			let new_path = self.exe.with_extension("new");
+			{
+				let mut file = fs::File::create(&new_path)?;
+				file.write_all(binary)?;
+				file.set_permissions(fs::Permissions::from_mode(0o755))?;
+				file.sync_all()?;
+			}
+			let pending = PendingUpdate {
+				version: version.to_owned(),
+				roll_back_at: unix_time() + rollback_after.as_secs(),
+			};
+			fs::write(&self.pending_file, serde_json::to_string(&pending).expect("Could not serialize pending update"))?;
+			fs::rename(&new_path, &self.exe)
+		};
+		swap().map_err(|err| {
+			let _ = fs::remove_file(&self.pending_file);
+			err
+		})
+	}
+}
+
+/// Installs signed releases offered by the manager
+pub struct Updater {
+	release_key: Option<PublicKey>,
+	rollback_after: time::Duration,
+	watchdog: Arc<Watchdog>,
+	failed_versions: Mutex<HashSet<String>>,
+}
+
+impl Updater {
+	/// `release_key` is hex. Updates are turned off without one.
+	pub fn new(release_key: Option<&str>, rollback_minutes: u64, watchdog: &Arc<Watchdog>) -> Self {
+		let release_key = release_key.map(|key| {
+			let key = hex::decode(key).expect("Invalid release key");
+			PublicKey::from_bytes(&key).expect("Invalid release key")
+		});
+		Self {
+			release_key,
+			rollback_after: time::Duration::from_secs(rollback_minutes * 60),
+			watchdog: Arc::clone(watchdog),
+			failed_versions: Mutex::new(watchdog.failed_versions()),
+		}
+	}
+
+	/// Spawns a thread that installs releases the manager offers in heartbeat responses
+	pub fn start_installing(updater: &Arc<Updater>, manager: &Arc<ManagerAPI>) {
+		if updater.release_key.is_none() {
+			println!("No release key configured, automatic updates are off");
+			return;
+		}
+		let updater = Arc::clone(updater);
+		let manager = Arc::clone(manager);
+		thread::spawn(move || {
+			loop {
+				thread::sleep(time::Duration::from_secs(10));
+				let release = match manager.take_offered_release() {
+					Some(release) => release,
+					None => continue,
+				};
+				if updater.failed_versions.lock().unwrap().contains(&release.version) {
+					continue;
+				}
+				match updater.install(&manager, &release) {
+					Ok(()) => {
+						println!("Installed version {}, restarting", &release.version);
+						let err = peripherals::exec_binary(&updater.watchdog.exe);
+						eprintln!("Could not start new version: {:?}", err);
+					},
+					Err(err) => {
+						eprintln!("Could not install version {}: {}", &release.version, err);
+						// Don't download it again on every heartbeat
+						updater.failed_versions.lock().unwrap().insert(release.version);
+					},
+				}
+			}
+		});
+	}
+
+	fn install(&self, manager: &ManagerAPI, release: &Release) -> Result<(), String> {
+		let release_key = self.release_key.as_ref().ok_or("No release key configured")?;
+		if compare_versions(&release.version, CURRENT_VERSION) != Some(Ordering::Greater) {
+			return Err(format!("Not newer than {}", CURRENT_VERSION));
+		}
+		println!("Downloading version {}", &release.version);
+		let binary = manager.download_release(&release.version).map_err(|err| format!("{:?}", err))?;
+		release.verify(&binary, release_key)?;
+		self.watchdog.swap_in(&binary, &release.version, self.rollback_after).map_err(|err| format!("{:?}", err))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::rngs::OsRng;
+	use ed25519_dalek::Keypair;
+	use super::*;
+
+	fn signed_release(keypair: &Keypair, version: &str, binary: &[u8]) -> Release {
+		let sha256 = crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, binary);
+		let signature = keypair.sign(signed_message(version, &sha256).as_bytes());
+		Release {
+			version: version.to_owned(),
+			sha256,
+			signature: hex::encode(&signature.to_bytes()[..]),
+		}
+	}
+
+	#[test]
+	fn compares_versions_numerically() {
+		assert_eq!(compare_versions("0.1.10", "0.1.9"), Some(Ordering::Greater));
+		assert_eq!(compare_versions("0.2.0", "0.10.0"), Some(Ordering::Less));
+		assert_eq!(compare_versions("1.0.0", "1.0.0"), Some(Ordering::Equal));
+		assert_eq!(compare_versions("1.0.0-beta", "1.0.0"), None);
+	}
+
+	#[test]
+	fn verifies_signed_releases() {
+		let mut csprng = OsRng::new().unwrap();
+		let keypair = Keypair::generate(&mut csprng);
+		let binary = b"new build";
+		let release = signed_release(&keypair, "0.2.0", binary);
+		assert_eq!(release.verify(binary, &keypair.public), Ok(()));
+
+		assert!(release.verify(b"tampered build", &keypair.public).is_err());
+		let other = Keypair::generate(&mut csprng);
+		assert!(release.verify(binary, &other.public).is_err());
+		// Relabeling a signed build as a different version breaks the signature
+		let relabeled = Release { version: "9.9.9".to_owned(), ..release };
+		assert!(relabeled.verify(binary, &keypair.public).is_err());
+	}
+
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("checkin-releases-{}-{}", std::process::id(), name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn swaps_in_new_builds_and_restores_the_previous_one() {
+		let dir = temp_dir("swap");
+		let exe = dir.join(BINARY_NAME);
+		fs::write(&exe, b"old build").unwrap();
+		let watchdog = Watchdog::new(exe.clone(), &dir);
+
+		watchdog.swap_in(b"new build", "9.9.9", time::Duration::from_secs(600)).unwrap();
+		assert_eq!(fs::read(&exe).unwrap(), b"new build");
+		assert_eq!(fs::metadata(&exe).unwrap().permissions().mode() & 0o777, 0o755);
+		assert_eq!(fs::read(watchdog.backup_path()).unwrap(), b"old build");
+		let pending = watchdog.pending_update().unwrap();
+		assert_eq!(pending.version, "9.9.9");
+		assert!(pending.roll_back_at >= unix_time() + 590);
+		// The backup is the only known good build so it can't be replaced until this one proves itself
+		assert!(watchdog.swap_in(b"newer build", "9.9.10", time::Duration::from_secs(600)).is_err());
+		assert_eq!(fs::read(watchdog.backup_path()).unwrap(), b"old build");
+
+		watchdog.restore_previous().unwrap();
+		assert_eq!(fs::read(&exe).unwrap(), b"old build");
+		assert!(watchdog.pending_update().is_none());
+		assert!(watchdog.failed_versions().contains(CURRENT_VERSION));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn watchdog_rolls_back_new_builds_that_run_out_of_time() {
+		let dir = temp_dir("watchdog");
+		let watchdog = Watchdog::new(dir.join(BINARY_NAME), &dir);
+		assert_eq!(watchdog.check(1000), WatchdogCheck::Idle);
+
+		let pending = PendingUpdate { version: CURRENT_VERSION.to_owned(), roll_back_at: 1000 };
+		fs::write(&watchdog.pending_file, serde_json::to_string(&pending).unwrap()).unwrap();
+		assert_eq!(watchdog.check(900), WatchdogCheck::Deadline(1000));
+		assert_eq!(watchdog.check(1000), WatchdogCheck::RollBack);
+
+		fs::write(watchdog.backup_path(), b"old build").unwrap();
+		watchdog.started();
+		assert!(watchdog.pending_update().is_none());
+		assert!(!watchdog.backup_path().exists());
+
+		// We're the old build, so the new one was already rolled back or never swapped in
+		let pending = PendingUpdate { version: "9.9.9".to_owned(), roll_back_at: 1000 };
+		fs::write(&watchdog.pending_file, serde_json::to_string(&pending).unwrap()).unwrap();
+		assert_eq!(watchdog.check(2000), WatchdogCheck::Idle);
+		assert!(watchdog.pending_update().is_none());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,124 @@
+use std::io::{ self, BufRead, Write };
+use std::sync::{ Arc, Mutex, mpsc };
+use std::thread;
+use crate::api::ManagerAPI;
+use crate::cards::{ CardEvent, parse_event };
+use crate::peripherals::{ Display, Buzzer, Notifier, Tone, Result };
+
+pub const SOCKET_PATH: &'static str = "./simulator.sock";
+
+// The matrices are drawn in a fixed area at the top of the terminal and log output scrolls underneath
+const SCREEN_LINES: usize = 11;
+
+/// Both 8x8 matrices as they are currently lit
+struct Screen {
+	// Left (success) then right (error), same as the physical layout
+	frames: [u64; 2],
+}
+
+impl Screen {
+	fn start() -> Self {
+		// Clear the terminal, limit scrolling to below the matrices and put the cursor there
+		print!("\x1b[2J\x1b[{}r\x1b[{};1H", SCREEN_LINES + 2, SCREEN_LINES + 2);
+		let screen = Screen { frames: [0; 2] };
+		screen.render();
+		screen
+	}
+
+	fn row(frame: u64, y: u8) -> String {
+		// Columns count up from right to left on the real hardware
+		(0..8).rev().map(|x| {
+			if frame & (1 << (y * 8 + x)) != 0 { "██" } else { " ·" }
+		}).collect()
+	}
+
+	fn render(&self) {
+		let mut output = String::new();
+		// Save cursor, draw at the top, then restore so that logging isn't disturbed
+		output.push_str("\x1b7\x1b[1;1H");
+		output.push_str(" success            error\x1b[K\n");
+		output.push_str("+----------------+ +----------------+\x1b[K\n");
+		for y in 0..8 {
+			output.push_str(&format!("|{}| |{}|\x1b[K\n", Screen::row(self.frames[0], y), Screen::row(self.frames[1], y)));
+		}
+		output.push_str("+----------------+ +----------------+\x1b[K");
+		output.push_str("\x1b8");
+		print!("{}", output);
+		io::stdout().flush().unwrap();
+	}
+}
+
+pub struct TerminalDisplay {
+	position: usize,
+	pixels: u64,
+	screen: Arc<Mutex<Screen>>,
+}
+
+impl Display for TerminalDisplay {
+	fn set_pixel(&mut self, x: u8, y: u8, on: bool) {
+		if x > 7 || y > 7 {
+			panic!("Pixel location out of range ({}, {})", x, y);
+		}
+		let bit = 1u64 << (y * 8 + x);
+		if on {
+			self.pixels |= bit;
+		}
+		else {
+			self.pixels &= !bit;
+		}
+	}
+
+	fn display_buffer(&mut self) -> Result<()> {
+		let mut screen = self.screen.lock().unwrap();
+		screen.frames[self.position] = self.pixels;
+		screen.render();
+		Ok(())
+	}
+
+	fn set_brightness(&self, _level: u8) -> Result<()> {
+		Ok(())
+	}
+}
+
+/// Prints tones instead of playing them but still takes as long so that sequences keep their timing
+pub struct TerminalBuzzer;
+
+impl Buzzer for TerminalBuzzer {
+	fn play(&mut self, tone: &Tone) {
+		if tone.frequency() != 0.0 {
+			println!("♪ {:.2} Hz for {} ms", tone.frequency(), tone.duration().as_millis());
+		}
+		thread::sleep(tone.duration());
+	}
+}
+
+pub fn notifier() -> Notifier {
+	let screen = Arc::new(Mutex::new(Screen::start()));
+	let success_display = TerminalDisplay { position: 0, pixels: 0, screen: Arc::clone(&screen) };
+	let error_display = TerminalDisplay { position: 1, pixels: 0, screen };
+	Notifier::new(Box::new(success_display), Box::new(error_display), Box::new(TerminalBuzzer))
+}
+
+/// Reads card events from stdin in the same format as the socket injector. "tag" presses the tag button.
+pub fn read_stdin(events: mpsc::Sender<CardEvent>, manager: &Arc<ManagerAPI>, notifier: &Arc<Notifier>) {
+	let manager = Arc::clone(manager);
+	let notifier = Arc::clone(notifier);
+	println!("Type a badge's user ID and press enter to tap it, or \"tag\" to press the tag button");
+	thread::spawn(move || {
+		let stdin = io::stdin();
+		for line in stdin.lock().lines() {
+			let line = match line {
+				Ok(line) => line,
+				Err(_) => break,
+			};
+			if line.trim() == "tag" {
+				manager.update_tag(&notifier);
+			}
+			else if let Some(event) = parse_event(&line) {
+				if events.send(event).is_err() {
+					break;
+				}
+			}
+		}
+	});
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::{ thread, time };
+use ed25519_dalek::PublicKey;
+use crate::api::{ self, ManagerAPI, ManagedStatus };
+use crate::crypto::Signer;
+use crate::peripherals::Notifier;
+
+/// Doubles the wait after every attempt that doesn't get us any further, up to a limit
+pub struct Backoff {
+	initial: time::Duration,
+	max: time::Duration,
+	current: time::Duration,
+}
+
+impl Backoff {
+	pub fn new(initial: time::Duration, max: time::Duration) -> Self {
+		Self { initial, max, current: initial }
+	}
+
+	/// How long to wait before the next attempt
+	pub fn next(&mut self) -> time::Duration {
+		let wait = self.current;
+		self.current = std::cmp::min(self.current * 2, self.max);
+		wait
+	}
+
+	pub fn reset(&mut self) {
+		self.current = self.initial;
+	}
+}
+
+/// Finishes a key rotation that we never heard the outcome of (see `Signer::generate_replacement`). A heartbeat signed with the
+/// new key tells us which key the manager has: it only answers normally for a device it knows. Returns the key to use.
+pub fn finish_key_rotation(signer: Signer, manager_url: &str, manager_key: Option<PublicKey>, notifier: &Notifier) -> Signer {
+	let replacement = match signer.pending_replacement() {
+		Some(replacement) => replacement,
+		None => return signer,
+	};
+	println!("Checking whether the manager switched to our new key");
+	let manager = ManagerAPI::new(manager_url, replacement.clone(), manager_key);
+	let mut backoff = Backoff::new(time::Duration::from_secs(5), time::Duration::from_secs(60));
+	loop {
+		match manager.heartbeat() {
+			Ok(()) => match signer.replace_with(replacement.clone()) {
+				Ok(signer) => {
+					println!("Switched to the new key");
+					return signer;
+				},
+				Err(err) => eprintln!("Could not switch to the new key: {:?}", err),
+			},
+			Err(api::Error::Revoked) => {
+				println!("Manager still has our current key");
+				if let Err(err) = signer.discard_replacement() {
+					eprintln!("Could not remove the new key: {:?}", err);
+				}
+				return signer;
+			},
+			Err(err) => println!("Key rotation check: {:?}", err),
+		}
+		// Can't check in with either key until we know which one the manager has
+		notifier.scroll_text_speed("Finishing key change", 30);
+		thread::sleep(backoff.next());
+	}
+}
+
+/// Where we are in getting set up with the manager before check-ins can start
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+	/// Can't reach the manager (network might not be up yet)
+	Connecting,
+	/// Waiting for an admin to authorize us in the manager UI
+	WaitingForApproval,
+	/// Rejected in the manager UI. Still checked in case someone changes their mind.
+	Denied,
+}
+
+/// Keeps asking the manager for our status until we're authorized, showing what we're waiting on in the meantime
+pub fn wait_for_authorization(manager: &ManagerAPI, signer: &Signer, notifier: &Arc<Notifier>) -> ManagedStatus {
+	// Typed into the manager UI to approve this device
+	let pairing_code = signer.get_pairing_code();
+	let mut state = State::Connecting;
+	let mut backoff = Backoff::new(time::Duration::from_secs(5), time::Duration::from_secs(60));
+	loop {
+		let next_state = match manager.initialize() {
+			Ok(ManagedStatus::Pending) => State::WaitingForApproval,
+			Ok(ManagedStatus::Unauthorized) => State::Denied,
+			Ok(status) => return status,
+			Err(err) => {
+				println!("Initialize: {:?}", err);
+				State::Connecting
+			},
+		};
+		if next_state != state {
+			// Check again quickly after anything changes
+			backoff.reset();
+			state = next_state;
+			match state {
+				State::Connecting => eprintln!("Lost connection to the manager"),
+				State::WaitingForApproval => eprintln!("Check-in instance <{}> must be approved in the manager UI before use with pairing code {}", manager.get_name(), &pairing_code),
+				State::Denied => eprintln!("Check-in instance <{}> has been denied access in the manager UI", manager.get_name()),
+			}
+		}
+
+		let wait = backoff.next();
+		match state {
+			State::Connecting => {
+				const FLASH_TIME: u64 = 500; // milliseconds
+				let start = time::Instant::now();
+				while start.elapsed() < wait {
+					notifier.flash_alternate(vec![FLASH_TIME, FLASH_TIME], notifier);
+					thread::sleep(time::Duration::from_millis(FLASH_TIME * 2));
+				}
+			},
+			State::WaitingForApproval => {
+				notifier.scroll_text_speed("Waiting for approval", 30);
+				notifier.scroll_text_speed(&pairing_code, 60);
+				thread::sleep(wait);
+			},
+			State::Denied => {
+				notifier.scroll_text_speed("Denied access in manager UI", 30);
+				thread::sleep(wait);
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_doubles_up_to_max_and_resets() {
+		let mut backoff = Backoff::new(time::Duration::from_secs(5), time::Duration::from_secs(60));
+		let waits: Vec<u64> = (0..6).map(|_| backoff.next().as_secs()).collect();
+		assert_eq!(waits, vec![5, 10, 20, 40, 60, 60]);
+		backoff.reset();
+		assert_eq!(backoff.next().as_secs(), 5);
+	}
+}
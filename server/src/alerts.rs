@@ -0,0 +1,243 @@
+use std::{ thread, time };
+use chrono::{ DateTime, Duration, Utc };
+use serde::Serialize;
+use wither::model::Model;
+use crate::DB;
+use crate::models::Device;
+
+pub enum Alert<'a> {
+    Offline(&'a Device),
+    Recovered(&'a Device),
+}
+
+impl<'a> Alert<'a> {
+    fn device(&self) -> &Device {
+        match self {
+            Alert::Offline(device) | Alert::Recovered(device) => device,
+        }
+    }
+
+    fn event(&self) -> &'static str {
+        match self {
+            Alert::Offline(_) => "offline",
+            Alert::Recovered(_) => "recovered",
+        }
+    }
+
+    fn message(&self) -> String {
+        let device = self.device();
+        let last_seen = device.last_seen
+            .map(|last_seen| last_seen.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or(String::from("never"));
+        match self {
+            Alert::Offline(_) => format!("Check-in device {} ({}) has gone offline. Last seen: {}", device.friendly_name, device.ip_address, last_seen),
+            Alert::Recovered(_) => format!("Check-in device {} ({}) is back online", device.friendly_name, device.ip_address),
+        }
+    }
+}
+
+/// Somewhere that offline / recovered alerts can be sent
+pub trait Notifier: Send {
+    fn notify(&self, alert: &Alert) -> Result<(), String>;
+}
+
+/// Prints alerts to the server's log
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        eprintln!("[alert] {}", alert.message());
+        Ok(())
+    }
+}
+
+/// POSTs alerts as JSON to a URL. The `text` field means Slack-style incoming webhooks work out of the box.
+pub struct WebhookNotifier {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Some(time::Duration::from_secs(10)))
+            .build()
+            .expect("Failed to construct webhook client");
+        Self { url, client }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        #[derive(Serialize)]
+        struct Payload<'a> {
+            text: String,
+            event: &'static str,
+            device: &'a str,
+            username: &'a str,
+            ip_address: &'a str,
+        }
+        let device = alert.device();
+        let payload = Payload {
+            text: alert.message(),
+            event: alert.event(),
+            device: &device.friendly_name,
+            username: &device.username,
+            ip_address: &device.ip_address,
+        };
+        let response = self.client.post(&self.url)
+            .json(&payload)
+            .send()
+            .map_err(|err| format!("{:?}", err))?;
+        if !response.status().is_success() {
+            return Err(format!("Webhook responded with {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Periodically looks for authorized devices that have stopped sending heartbeats
+pub struct OfflineMonitor {
+    db: DB,
+    alerts: OfflineAlerts,
+}
+
+/// Decides when a device has gone offline or come back and sends out alerts for it
+struct OfflineAlerts {
+    threshold: Duration,
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+impl OfflineMonitor {
+    /// Configured by OFFLINE_ALERT_MINUTES (default 5) and ALERT_WEBHOOK_URL (optional)
+    pub fn from_env(db: DB) -> Self {
+        let minutes = std::env::var("OFFLINE_ALERT_MINUTES").ok()
+            .map(|minutes| minutes.parse().expect("Invalid OFFLINE_ALERT_MINUTES"))
+            .unwrap_or(5);
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(LogNotifier)];
+        if let Ok(url) = std::env::var("ALERT_WEBHOOK_URL") {
+            notifiers.push(Box::new(WebhookNotifier::new(url)));
+        }
+        Self {
+            db,
+            alerts: OfflineAlerts {
+                threshold: Duration::minutes(minutes),
+                notifiers,
+            },
+        }
+    }
+
+    pub fn start(self) {
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = self.check() {
+                    eprintln!("Offline device check failed: {:?}", err);
+                }
+                thread::sleep(time::Duration::from_secs(60));
+            }
+        });
+    }
+
+    fn check(&self) -> Result<(), mongodb::error::Error> {
+        let devices = match Device::find(self.db.clone(), Some(doc! { "authorized": true }), None) {
+            Ok(result) => result,
+            // Driver returns an error if no documents are found
+            Err(_) => Vec::new(),
+        };
+        let now = Utc::now();
+        for device in devices {
+            if let Some(offline_alerted) = self.alerts.check(&device, now) {
+                device.update(self.db.clone(), None, doc! { "$set": { "offline_alerted": offline_alerted } }, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OfflineAlerts {
+    /// Sends an alert if the device has gone offline or come back since the last check and returns what `offline_alerted` should now be
+    fn check(&self, device: &Device, now: DateTime<Utc>) -> Option<bool> {
+        // Devices that have never sent a heartbeat (e.g. older clients) aren't tracked
+        let silent = match device.last_seen {
+            Some(last_seen) => now.signed_duration_since(last_seen) > self.threshold,
+            None => false,
+        };
+        if silent && !device.offline_alerted {
+            self.send(&Alert::Offline(device));
+            Some(true)
+        }
+        else if !silent && device.offline_alerted {
+            self.send(&Alert::Recovered(device));
+            Some(false)
+        }
+        else {
+            None
+        }
+    }
+
+    fn send(&self, alert: &Alert) {
+        for notifier in self.notifiers.iter() {
+            if let Err(err) = notifier.notify(alert) {
+                eprintln!("Failed to send {} alert: {}", alert.event(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+    use super::*;
+
+    struct Recording(Arc<Mutex<Vec<String>>>);
+
+    impl Notifier for Recording {
+        fn notify(&self, alert: &Alert) -> Result<(), String> {
+            self.0.lock().unwrap().push(format!("{} {}", alert.event(), alert.device().username));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn alerts_once_per_outage() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let alerts = OfflineAlerts {
+            threshold: Duration::minutes(5),
+            notifiers: vec![Box::new(Recording(Arc::clone(&sent)))],
+        };
+        let mut device: Device = serde_json::from_value(serde_json::json!({
+            "public_key": "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c",
+            "friendly_name": "test",
+            "username": "test",
+            "ip_address": "127.0.0.1",
+            "authorized": true,
+            "pending": false,
+            "credentials_created": true,
+        })).unwrap();
+        let now = Utc::now();
+        // Never sent a heartbeat
+        assert_eq!(alerts.check(&device, now), None);
+
+        device.last_seen = Some(now - Duration::minutes(1));
+        assert_eq!(alerts.check(&device, now), None);
+        device.last_seen = Some(now - Duration::minutes(10));
+        assert_eq!(alerts.check(&device, now), Some(true));
+        assert_eq!(*sent.lock().unwrap(), vec!["offline test"]);
+
+        // Still down on the next checks
+        device.offline_alerted = true;
+        assert_eq!(alerts.check(&device, now), None);
+        assert_eq!(alerts.check(&device, now + Duration::minutes(1)), None);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        device.last_seen = Some(now);
+        assert_eq!(alerts.check(&device, now), Some(false));
+        assert_eq!(*sent.lock().unwrap(), vec!["offline test", "recovered test"]);
+        device.offline_alerted = false;
+        assert_eq!(alerts.check(&device, now), None);
+
+        // A later outage gets its own alert
+        assert_eq!(alerts.check(&device, now + Duration::minutes(10)), Some(true));
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+}
@@ -1,23 +1,35 @@
 use std::ops::{ Deref, DerefMut };
 use std::io::Read;
+use std::sync::Mutex;
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
 use rocket::request::{ self, Request, FromRequest };
 use rocket::{ Data, Outcome, State };
 use rocket::http::{ Status, ContentType };
 use rocket::data::{ self, FromDataSimple };
+use rocket::response::NamedFile;
 use rocket_contrib::json::{ Json, JsonValue };
-use serde::Deserialize;
+use serde::{ Serialize, Deserialize };
 use serde::de::DeserializeOwned;
 use ed25519_dalek::{ PublicKey, Signature };
+use chrono::{ DateTime, Utc };
+use mongodb::oid::ObjectId;
+use mongodb::coll::options::FindOptions;
 use wither::model::Model;
 use hackgt_nfc::api::CheckinAPI;
 use crate::DB;
-use crate::models::Device;
+use crate::models::{ Device, DeviceSettings, ToneTheme, DeviceCommand, CommandKind, User, Role, AuditEvent, AuditAction };
 use crate::auth::AuthenticatedUser;
+use crate::csrf::CsrfVerified;
+use crate::updates::DeviceUpdates;
+use crate::signing::{ ServerKey, RequestBodyHash };
+use crate::attendees::AttendeeSnapshots;
+use crate::releases::Releases;
 
 pub struct IP(String);
 
 impl IP {
-    fn as_str(&self) -> &str {
+    pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
 }
@@ -35,20 +47,20 @@ impl<'a, 'r> FromRequest<'a, 'r> for IP {
 }
 
 // Implement signature authentication for JSON bodies
-#[derive(Debug)]
-pub struct SignedRequest<T> {
+pub struct SignedRequest<T, A: DeviceAccess = AuthorizedDevice> {
     pub public_key: String,
+    pub device: A::Device,
     content: T,
 }
 
-impl<T> Deref for SignedRequest<T> {
+impl<T, A: DeviceAccess> Deref for SignedRequest<T, A> {
     type Target = T;
     #[inline(always)]
     fn deref(&self) -> &T {
         &self.content
     }
 }
-impl<T> DerefMut for SignedRequest<T> {
+impl<T, A: DeviceAccess> DerefMut for SignedRequest<T, A> {
     #[inline(always)]
     fn deref_mut(&mut self) -> &mut T {
         &mut self.content
@@ -61,9 +73,144 @@ pub enum SignedRequestError {
     Invalid,
     InvalidBody,
     Unauthorized,
+    UnknownDevice,
+    Replayed,
+    /// Too many recent nonces (overall or from this device) to remember another one
+    Busy,
+    TooLarge,
+    DBError(mongodb::error::Error),
+}
+
+// Every signed body carries a timestamp and a random nonce alongside the actual request fields
+#[derive(Deserialize)]
+struct SignedBody<T> {
+    timestamp: u64,
+    nonce: String,
+    #[serde(flatten)]
+    content: T,
+}
+
+/// Remembers recently used nonces so that captured signed requests can't be replayed
+pub struct NonceCache {
+    seen: Mutex<SeenNonces>,
+    capacity: usize,
+    per_device: usize,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<String>,
+    // Queue of (time seen, public key, nonce) so the oldest can be evicted once they expire
+    order: VecDeque<(u64, String, String)>,
+    per_device: HashMap<String, usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum NonceCheck {
+    Fresh,
+    /// Timestamp is too far from our clock
+    Stale,
+    Replayed,
+    /// Every remembered nonce is still needed. Forgetting one would let its request be replayed.
+    Full,
+    /// This device has used up its share of the cache, so one leaked key can't lock out every other device
+    DeviceFull,
+}
+
+impl NonceCache {
+    /// How far (in seconds) a request's timestamp may drift from the server's clock
+    pub const MAX_AGE: u64 = 5 * 60;
+
+    /// `per_device` should comfortably cover a device polling at `DeviceSettings::MIN_POLL_INTERVAL` for 2 * MAX_AGE
+    pub fn new(capacity: usize, per_device: usize) -> Self {
+        Self {
+            seen: Mutex::new(SeenNonces::default()),
+            capacity,
+            per_device,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn is_fresh(timestamp: u64, now: u64) -> bool {
+        // Timestamps come from the client so they can be anywhere up to u64::MAX
+        timestamp.saturating_add(NonceCache::MAX_AGE) >= now && timestamp <= now.saturating_add(NonceCache::MAX_AGE)
+    }
+
+    /// Records the nonce if the request is fresh and its nonce hasn't been used yet
+    fn check(&self, public_key: &str, timestamp: u64, nonce: &str) -> NonceCheck {
+        self.check_at(NonceCache::now(), public_key, timestamp, nonce)
+    }
+
+    fn check_at(&self, now: u64, public_key: &str, timestamp: u64, nonce: &str) -> NonceCheck {
+        if !NonceCache::is_fresh(timestamp, now) {
+            return NonceCheck::Stale;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        let SeenNonces { nonces, order, per_device } = &mut *seen;
+        // A nonce seen more than 2 * MAX_AGE ago can only be replayed with a timestamp we'd reject as stale
+        while order.front().map(|(seen_at, _, _)| seen_at.saturating_add(2 * NonceCache::MAX_AGE) < now).unwrap_or(false) {
+            let (_, device, nonce) = order.pop_front().unwrap();
+            nonces.remove(&nonce);
+            if let Some(count) = per_device.get_mut(&device) {
+                *count -= 1;
+                if *count == 0 {
+                    per_device.remove(&device);
+                }
+            }
+        }
+        if nonces.contains(nonce) {
+            return NonceCheck::Replayed;
+        }
+        if order.len() >= self.capacity {
+            return NonceCheck::Full;
+        }
+        let count = per_device.entry(public_key.to_owned()).or_insert(0);
+        if *count >= self.per_device {
+            return NonceCheck::DeviceFull;
+        }
+        *count += 1;
+        nonces.insert(nonce.to_owned());
+        order.push_back((now, public_key.to_owned(), nonce.to_owned()));
+        NonceCheck::Fresh
+    }
+}
+
+// Decides which devices a SignedRequest will let through to the handler
+pub trait DeviceAccess {
+    type Device;
+    fn check(device: Option<Device>) -> Result<Self::Device, (Status, SignedRequestError)>;
 }
 
-impl<T: DeserializeOwned> FromDataSimple for SignedRequest<T> {
+/// Only devices that have been authorized in the manager UI (default)
+pub struct AuthorizedDevice;
+impl DeviceAccess for AuthorizedDevice {
+    type Device = Device;
+    fn check(device: Option<Device>) -> Result<Device, (Status, SignedRequestError)> {
+        match device {
+            Some(device) if device.authorized => Ok(device),
+            Some(_) => Err((Status::Forbidden, SignedRequestError::Unauthorized)),
+            None => Err((Status::Unauthorized, SignedRequestError::UnknownDevice)),
+        }
+    }
+}
+
+/// Any validly signed request, including from devices we haven't seen before
+pub struct AnyDevice;
+impl DeviceAccess for AnyDevice {
+    type Device = Option<Device>;
+    fn check(device: Option<Device>) -> Result<Option<Device>, (Status, SignedRequestError)> {
+        Ok(device)
+    }
+}
+
+/// Signed requests are small JSON objects so anything bigger is turned away without reading the rest of it
+const MAX_SIGNED_BODY: u64 = 64 * 1024;
+
+impl<T: DeserializeOwned, A: DeviceAccess> FromDataSimple for SignedRequest<T, A> {
     type Error = SignedRequestError;
 
     fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
@@ -72,6 +219,16 @@ impl<T: DeserializeOwned> FromDataSimple for SignedRequest<T> {
             return Outcome::Forward(data);
         }
 
+        // Read up front so that the response signature covers the body even when the request is rejected
+        let mut body = Vec::new();
+        if let Err(_) = data.open().take(MAX_SIGNED_BODY + 1).read_to_end(&mut body) {
+            return Outcome::Failure((Status::Unauthorized, SignedRequestError::InvalidBody));
+        }
+        request.local_cache(|| RequestBodyHash::of(&body));
+        if body.len() as u64 > MAX_SIGNED_BODY {
+            return Outcome::Failure((Status::PayloadTooLarge, SignedRequestError::TooLarge));
+        }
+
         let auth: Option<&str> = request.headers().get("Authorization").next();
         match auth {
             Some(auth) => {
@@ -86,11 +243,6 @@ impl<T: DeserializeOwned> FromDataSimple for SignedRequest<T> {
                     if public_key.is_none() || signature.is_none() {
                         return Outcome::Failure((Status::Unauthorized, SignedRequestError::Invalid));
                     }
-                    // Get DB connection from Rocket request state
-                    // let devices = request.guard::<State<DB>>().unwrap().collection("devices");
-                    // let result = devices.find_one(Some(doc!{ "name": *public_key.unwrap() }), None);
-
-                    // TODO: Check for unauthorized public keys
                     let raw_public_key = String::from(*public_key.unwrap());
                     let public_key = match hex::decode(public_key.unwrap()) {
                         Ok(key) => key,
@@ -109,18 +261,39 @@ impl<T: DeserializeOwned> FromDataSimple for SignedRequest<T> {
                         Err(_) => return Outcome::Failure((Status::Unauthorized, SignedRequestError::Invalid)),
                     };
                     // Verify signature
-                    let mut body = Vec::new();
-                    if let Err(_) = data.open().read_to_end(&mut body) {
-                        return Outcome::Failure((Status::Unauthorized, SignedRequestError::InvalidBody));
-                    }
                     if public_key.verify(&body, &signature).is_err() {
                         return Outcome::Failure((Status::Unauthorized, SignedRequestError::Invalid));
                     }
-                    // Parse JSON
-                    match serde_json::from_slice(&body) {
-                        Ok(json) => Outcome::Success(SignedRequest { public_key: raw_public_key, content: json }),
-                        Err(_) => Outcome::Failure((Status::Unauthorized, SignedRequestError::InvalidBody)),
+                    let body: SignedBody<T> = match serde_json::from_slice(&body) {
+                        Ok(json) => json,
+                        Err(_) => return Outcome::Failure((Status::Unauthorized, SignedRequestError::InvalidBody)),
+                    };
+                    if !NonceCache::is_fresh(body.timestamp, NonceCache::now()) {
+                        return Outcome::Failure((Status::Unauthorized, SignedRequestError::Replayed));
+                    }
+                    // Only hit the database once we know the key holder actually signed this request
+                    let db = request.guard::<State<DB>>().unwrap();
+                    let device = match Device::find_one(db.clone(), Some(doc! { "public_key": &raw_public_key }), None) {
+                        Ok(device) => device,
+                        Err(err) => return Outcome::Failure((Status::InternalServerError, SignedRequestError::DBError(err))),
+                    };
+                    let authorized = device.as_ref().map(|device| device.authorized).unwrap_or(false);
+                    let device = match A::check(device) {
+                        Ok(device) => device,
+                        Err(failure) => return Outcome::Failure(failure),
+                    };
+                    // Recorded last so that requests we turn away anyway don't take up room. Routes that take AnyDevice only
+                    // tell unauthorized devices their status (and update the IP of pending ones) so replaying those is harmless,
+                    // and skipping them means that throwaway keys can't fill the cache and lock out real devices.
+                    if authorized {
+                        let nonces = request.guard::<State<NonceCache>>().unwrap();
+                        match nonces.check(&raw_public_key, body.timestamp, &body.nonce) {
+                            NonceCheck::Fresh => {},
+                            NonceCheck::Full | NonceCheck::DeviceFull => return Outcome::Failure((Status::ServiceUnavailable, SignedRequestError::Busy)),
+                            NonceCheck::Stale | NonceCheck::Replayed => return Outcome::Failure((Status::Unauthorized, SignedRequestError::Replayed)),
+                        }
                     }
+                    Outcome::Success(SignedRequest { public_key: raw_public_key, device, content: body.content })
                 }
             },
             None => Outcome::Failure((Status::Unauthorized, SignedRequestError::Missing))
@@ -134,8 +307,8 @@ pub struct InitializeRequest {
 }
 
 #[post("/initialize", format = "json", data = "<request>")]
-pub fn initialize(request: SignedRequest<InitializeRequest>, db: State<DB>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
-    match Device::find_one(db.clone(), Some(doc! { "public_key": &request.public_key }), None)? {
+pub fn initialize(request: SignedRequest<InitializeRequest, AnyDevice>, db: State<DB>, key: State<ServerKey>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    match request.device.clone() {
         // Device already requested access, return status
         Some(device) => {
             let status: &'static str = if device.pending {
@@ -159,7 +332,8 @@ pub fn initialize(request: SignedRequest<InitializeRequest>, db: State<DB>, ip:
                 None
             )?;
             Ok(json!({
-                "status": status
+                "status": status,
+                "server_key": key.public_key(),
             }))
         },
         // Device is brand new to us
@@ -178,11 +352,21 @@ pub fn initialize(request: SignedRequest<InitializeRequest>, db: State<DB>, ip:
                 credentials_created: false,
 
                 current_tag: None,
+
+                last_seen: None,
+                uptime: None,
+                client_version: None,
+                reader_connected: None,
+                reported_tag: None,
+                offline_alerted: false,
+
+                settings: DeviceSettings::default(),
             };
             device.save(db.clone(), None).unwrap();
 
             Ok(json!({
-                "status": "Pending"
+                "status": "Pending",
+                "server_key": key.public_key(),
             }))
         }
     }
@@ -195,38 +379,322 @@ pub struct CredentialsRequest {
 }
 
 #[post("/credentials", format = "json", data = "<request>")]
-pub fn create_credentials(request: SignedRequest<CredentialsRequest>, db: State<DB>, checkin_api: State<CheckinAPI>) -> Result<JsonValue, mongodb::error::Error> {
-    match Device::find_one(db.clone(), Some(doc! { "public_key": &request.public_key }), None)? {
-        Some(device) => {
-            if device.pending || !device.authorized {
-                return Ok(json!({
-                    "error": "Unauthorized or pending device"
-                }));
-            }
-            let response = match checkin_api.add_user(&request.username, &request.password) {
-                Ok(_) => {
-                    device.update(
-                        db.clone(),
-                        None,
-                        doc! { "$set": { "credentials_created": true } },
-                        None
-                    )?;
-                    json!({
-                        "success": true,
-                    })
-                },
-                Err(err) => json!({
-                    "error": "Failed to create user with credentials",
-                    "details": format!("{:?}", err),
-                }),
-            };
-            Ok(response)
+pub fn create_credentials(request: SignedRequest<CredentialsRequest>, db: State<DB>, checkin_api: State<CheckinAPI>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    // Only authorized devices make it past the SignedRequest guard
+    let response = match checkin_api.add_user(&request.username, &request.password) {
+        Ok(_) => {
+            request.device.clone().update(
+                db.clone(),
+                None,
+                doc! { "$set": { "credentials_created": true } },
+                None
+            )?;
+            record_event(&db, &ip, &request.device.username, AuditAction::CreateCredentials, Some(&request.device), None, None)?;
+            json!({
+                "success": true,
+            })
         },
-        _ => {
-            Ok(json!({
-                "error": "Unknown device"
-            }))
+        Err(err) => json!({
+            "error": "Failed to create user with credentials",
+            "details": format!("{:?}", err),
+        }),
+    };
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct HeartbeatRequest {
+    uptime: i64,
+    client_version: String,
+    reader_connected: bool,
+    current_tag: Option<String>,
+}
+
+#[post("/heartbeat", format = "json", data = "<request>")]
+pub fn heartbeat(request: SignedRequest<HeartbeatRequest, AnyDevice>, db: State<DB>, releases: State<Releases>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    let device = match request.device.clone() {
+        Some(device) if device.authorized => device,
+        // Tells devices that were rejected or deleted while running to stop taking check-ins
+        _ => return Ok(json!({
+            "success": false,
+            "revoked": true,
+        })),
+    };
+    device.update(
+        db.clone(),
+        None,
+        doc! { "$set": {
+            "last_seen": mongodb::to_bson(&Utc::now())?,
+            "uptime": request.uptime,
+            "client_version": request.client_version.clone(),
+            "reader_connected": request.reader_connected,
+            "reported_tag": mongodb::to_bson(&request.current_tag)?,
+            "ip_address": ip.as_str(),
+        } },
+        None
+    )?;
+    Ok(json!({
+        "success": true,
+        // Devices check the signature themselves before installing anything
+        "release": releases.newer_than(&request.client_version),
+    }))
+}
+
+#[get("/releases/<version>")]
+pub fn download_release(version: String, releases: State<Releases>) -> Option<NamedFile> {
+    NamedFile::open(releases.binary(&version)?).ok()
+}
+
+#[derive(Deserialize)]
+pub struct UpdatesRequest {
+    version: Option<String>,
+}
+
+// Commands the device hasn't acknowledged yet, each signed with the server key so the device knows they came from us
+fn pending_commands(db: &DB, key: &ServerKey, username: &str) -> Result<Vec<JsonValue>, mongodb::error::Error> {
+    #[derive(Serialize)]
+    struct SignedCommand<'a> {
+        id: String,
+        device: &'a str,
+        command: CommandKind,
+        message: &'a Option<String>,
+        // Devices ignore commands older than DeviceCommand::EXPIRY_MINUTES so that a captured one can't be replayed later
+        issued_at: DateTime<Utc>,
+    }
+    let mut options = FindOptions::new();
+    options.sort = Some(doc! { "issued_at": -1 });
+    options.limit = Some(20);
+    let commands = match DeviceCommand::find(db.clone(), Some(doc! { "device": username }), Some(options)) {
+        Ok(result) => result,
+        // Driver returns an error if no documents are found
+        Err(_) => Vec::new(),
+    };
+
+    let mut signed_commands = Vec::new();
+    // Oldest first so that they run in the order they were sent
+    for command in commands.into_iter().rev() {
+        if command.acknowledged_at.is_some() || command.is_expired() {
+            continue;
+        }
+        let data = serde_json::to_string(&SignedCommand {
+            id: command.id.as_ref().map(ObjectId::to_hex).unwrap_or_default(),
+            device: &command.device,
+            command: command.command,
+            message: &command.message,
+            issued_at: command.issued_at,
+        }).expect("Could not serialize command");
+        signed_commands.push(json!({
+            "signature": key.sign(data.as_bytes()),
+            "data": data,
+        }));
+        if command.delivered_at.is_none() {
+            command.update(db.clone(), None, doc! { "$set": { "delivered_at": mongodb::to_bson(&Utc::now())? } }, None)?;
+        }
+    }
+    Ok(signed_commands)
+}
+
+// Long-poll that returns as soon as something about the device changes (or after 25 seconds)
+#[post("/updates", format = "json", data = "<request>")]
+pub fn wait_for_updates(request: SignedRequest<UpdatesRequest, AnyDevice>, db: State<DB>, updates: State<DeviceUpdates>, key: State<ServerKey>) -> Result<JsonValue, mongodb::error::Error> {
+    // Rejecting or deleting a device wakes this up so that it hears about it right away
+    let username = match &request.device {
+        Some(device) if device.authorized => device.username.clone(),
+        _ => return Ok(json!({
+            "revoked": true,
+        })),
+    };
+    let version = updates.wait(&username, request.version.as_ref().map(String::as_str), Duration::from_secs(25));
+    // Load the device again since it has probably changed while we were waiting
+    let response = match Device::find_one(db.clone(), Some(doc! { "username": &username }), None)? {
+        Some(device) if device.authorized => json!({
+            "version": version,
+            "current": device.current_tag,
+            "settings": device.settings,
+            "commands": pending_commands(&db, &key, &device.username)?,
+        }),
+        _ => json!({
+            "revoked": true,
+        }),
+    };
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct CommandAcknowledgement {
+    id: String,
+    success: bool,
+    details: Option<String>,
+}
+
+#[post("/commands/acknowledge", format = "json", data = "<request>")]
+pub fn acknowledge_command(request: SignedRequest<CommandAcknowledgement>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+    let id = match ObjectId::with_string(&request.id) {
+        Ok(id) => id,
+        Err(_) => return Ok(json!({
+            "success": false,
+            "error": "Invalid command ID",
+        })),
+    };
+    // Devices can only acknowledge their own commands
+    let command = DeviceCommand::find_one(db.clone(), Some(doc! { "_id": id, "device": &request.device.username }), None)?;
+    let response = match command {
+        Some(command) => {
+            // Acknowledgements can be retried so keep the first one
+            if command.acknowledged_at.is_none() {
+                command.update(
+                    db.clone(),
+                    None,
+                    doc! { "$set": {
+                        "acknowledged_at": mongodb::to_bson(&Utc::now())?,
+                        "success": request.success,
+                        "details": mongodb::to_bson(&request.details)?,
+                    } },
+                    None
+                )?;
+            }
+            json!({
+                "success": true,
+            })
         },
+        None => json!({
+            "success": false,
+            "error": "Command not found",
+        }),
+    };
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeyRequest {
+    /// The RotateKey command sent from the manager UI
+    command_id: String,
+    new_public_key: String,
+    /// Proves that the device holds the new key. The request as a whole is signed with the old one.
+    new_signature: String,
+    /// For the checkin2 account that goes with the new key
+    password: String,
+}
+
+// Must match `ManagerAPI::rotate_key` in the client
+fn rotation_message(old_public_key: &str, new_public_key: &str) -> String {
+    format!("checkin-embedded rotate key {} {}", old_public_key, new_public_key)
+}
+
+#[post("/rotate-key", format = "json", data = "<request>")]
+pub fn rotate_key(request: SignedRequest<RotateKeyRequest>, db: State<DB>, checkin_api: State<CheckinAPI>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    let device = &request.device;
+    // Devices can only rotate their keys when an admin asks them to
+    let command = match ObjectId::with_string(&request.command_id) {
+        Ok(id) => DeviceCommand::find_one(db.clone(), Some(doc! { "_id": id, "device": &device.username }), None)?,
+        Err(_) => None,
+    };
+    let command = match command {
+        Some(ref command) if command.command == CommandKind::RotateKey && command.acknowledged_at.is_none() && !command.is_expired() => command.clone(),
+        _ => return Ok(json!({
+            "success": false,
+            "error": "Key rotation wasn't requested",
+        })),
+    };
+
+    let new_key = hex::decode(&request.new_public_key).ok().and_then(|key| PublicKey::from_bytes(&key).ok());
+    let new_signature = hex::decode(&request.new_signature).ok().and_then(|signature| Signature::from_bytes(&signature).ok());
+    let new_key = match (new_key, new_signature) {
+        (Some(key), Some(signature)) if key.verify(rotation_message(&request.public_key, &request.new_public_key).as_bytes(), &signature).is_ok() => key,
+        _ => return Ok(json!({
+            "success": false,
+            "error": "Invalid signature from new key",
+        })),
+    };
+    if Device::find_one(db.clone(), Some(doc! { "public_key": &request.new_public_key }), None)?.is_some() {
+        return Ok(json!({
+            "success": false,
+            "error": "New key is already in use",
+        }));
+    }
+    let new_username = Device::username_for(&new_key.to_bytes());
+
+    // Created first so that the device can keep using its old key and account if this fails
+    if let Err(err) = checkin_api.add_user(&new_username, &request.password) {
+        return Ok(json!({
+            "success": false,
+            "error": "Failed to create user with new credentials",
+            "details": format!("{:?}", err),
+        }));
+    }
+    // The old account's password came from the old key so it has to go
+    if device.credentials_created {
+        if let Err(err) = checkin_api.delete_user(&device.username) {
+            // Back out so the device stays on its old key and account and can try again
+            if let Err(err) = checkin_api.delete_user(&new_username) {
+                eprintln!("Failed to delete unused checkin2 account {}: {:?}", &new_username, err);
+            }
+            return Ok(json!({
+                "success": false,
+                "error": "Failed to delete device's checkin2 account",
+                "details": format!("{:?}", err),
+            }));
+        }
+    }
+
+    // Keep names that were set in the manager UI
+    let friendly_name = if device.friendly_name == &device.username[..16] {
+        new_username[..16].to_owned()
+    }
+    else {
+        device.friendly_name.clone()
+    };
+    device.clone().update(
+        db.clone(),
+        None,
+        doc! { "$set": {
+            "public_key": &request.new_public_key,
+            "username": &new_username,
+            "friendly_name": friendly_name,
+            "credentials_created": true,
+        } },
+        None
+    )?;
+    record_event(&db, &ip, &device.username, AuditAction::RotateKey, Some(device), Some(device.username.clone()), Some(new_username.clone()))?;
+    // The device restarts with its new key as soon as we respond so it can't acknowledge this itself
+    command.update(
+        db.clone(),
+        None,
+        doc! { "$set": {
+            "acknowledged_at": mongodb::to_bson(&Utc::now())?,
+            "success": true,
+            "details": format!("Now {}", new_username),
+        } },
+        None
+    )?;
+    updates.notify(&device.username);
+
+    Ok(json!({
+        "success": true,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct AttendeesRequest {}
+
+// Signed list of attendees and whether they've checked in to the device's tag, used to validate badges while offline
+#[post("/attendees", format = "json", data = "<request>")]
+pub fn get_attendees(request: SignedRequest<AttendeesRequest>, checkin_api: State<CheckinAPI>, key: State<ServerKey>, snapshots: State<AttendeeSnapshots>) -> JsonValue {
+    let tag = match &request.device.current_tag {
+        Some(tag) => tag,
+        None => return json!({
+            "error": "No tag set for device",
+        }),
+    };
+    match snapshots.get(tag, &checkin_api, &key) {
+        Ok(signed) => json!({
+            "snapshot": signed.snapshot,
+            "signature": signed.signature,
+        }),
+        Err(err) => json!({
+            "error": "Failed to get attendees from check-in API",
+            "details": err,
+        }),
     }
 }
 
@@ -246,17 +714,81 @@ pub fn get_tag(username: Option<String>, db: State<DB>, checkin_api: State<Check
     }))
 }
 
+// Manager UI actions respond with this instead of running when the user's role isn't high enough
+fn require_role(user: &AuthenticatedUser, role: Role) -> Result<(), JsonValue> {
+    if user.has_role(role) {
+        Ok(())
+    }
+    else {
+        Err(json!({
+            "success": false,
+            "error": "Insufficient permissions",
+            "details": format!("Requires the {} role", role.as_str()),
+        }))
+    }
+}
+
+// Writes an entry to the audit log for an action taken through the manager
+fn record_event(db: &DB, ip: &IP, actor: &str, action: AuditAction, device: Option<&Device>, old_value: Option<String>, new_value: Option<String>) -> Result<(), mongodb::error::Error> {
+    let mut event = AuditEvent {
+        id: None,
+
+        action,
+        actor: actor.to_owned(),
+        device: device.map(|device| device.username.clone()),
+        device_name: device.map(|device| device.friendly_name.clone()),
+
+        old_value,
+        new_value,
+
+        ip_address: ip.as_str().to_owned(),
+        time: Utc::now(),
+    };
+    event.save(db.clone(), None)
+}
+
+fn status_name(device: &Device) -> String {
+    let status = if device.pending {
+        "Pending"
+    }
+    else if device.authorized {
+        "Authorized"
+    }
+    else {
+        "Unauthorized"
+    };
+    status.to_owned()
+}
+
 // Device actions called by JS in web UI
 #[derive(Deserialize)]
 pub struct DeviceButtonAction {
     username: String,
 }
 
+#[derive(Deserialize)]
+pub struct AuthorizeAction {
+    username: String,
+    /// Typed in from the device's displays
+    pairing_code: String,
+}
+
 #[post("/device/authorize", format = "json", data = "<request>")]
-pub fn authorize_device(user: AuthenticatedUser, request: Json<DeviceButtonAction>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn authorize_device(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<AuthorizeAction>, db: State<DB>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
-            device.update(
+            // Lets people type the code with the same spacing that the device scrolls it with
+            let pairing_code: String = request.pairing_code.chars().filter(char::is_ascii_digit).collect();
+            if device.pairing_code().as_ref() != Some(&pairing_code) {
+                return Ok(json!({
+                    "success": false,
+                    "error": "Pairing code doesn't match the one shown on the device",
+                }));
+            }
+            device.clone().update(
                 db.clone(),
                 None,
                 doc! { "$set": {
@@ -266,6 +798,7 @@ pub fn authorize_device(user: AuthenticatedUser, request: Json<DeviceButtonActio
                 } },
                 None
             )?;
+            record_event(&db, &ip, &user.username, AuditAction::Authorize, Some(&device), Some(status_name(&device)), Some("Authorized".to_owned()))?;
             json!({
                 "success": true,
             })
@@ -280,20 +813,43 @@ pub fn authorize_device(user: AuthenticatedUser, request: Json<DeviceButtonActio
     Ok(response)
 }
 
+/// Deletes a device's checkin2 account. checkin2 keeps a user's session tokens on the user, so this also ends any session the device
+/// already logged in with: its check-ins fail from then on even if it ignores being told that it was revoked.
+/// `deleting_checkin_account_ends_its_sessions` in main.rs checks this against a real checkin2 instance.
+fn delete_checkin_account(checkin_api: &CheckinAPI, username: &str) -> Result<(), JsonValue> {
+    checkin_api.delete_user(username).map(|_| ()).map_err(|err| json!({
+        "success": false,
+        "error": "Failed to delete device's checkin2 account",
+        "details": format!("{:?}", err),
+    }))
+}
+
 #[post("/device/reject", format = "json", data = "<request>")]
-pub fn reject_device(user: AuthenticatedUser, request: Json<DeviceButtonAction>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn reject_device(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceButtonAction>, db: State<DB>, checkin_api: State<CheckinAPI>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
-            device.update(
+            // Otherwise a running device could keep checking people in with its checkin2 account
+            if device.credentials_created {
+                if let Err(response) = delete_checkin_account(&checkin_api, &request.username) {
+                    return Ok(response);
+                }
+            }
+            device.clone().update(
                 db.clone(),
                 None,
                 doc! { "$set": {
                     "authorized": false,
                     "status_set_by": &user.username,
                     "pending": false,
+                    "credentials_created": false,
                 } },
                 None
             )?;
+            record_event(&db, &ip, &user.username, AuditAction::Reject, Some(&device), Some(status_name(&device)), Some("Unauthorized".to_owned()))?;
+            updates.notify(&request.username);
             json!({
                 "success": true,
             })
@@ -309,12 +865,15 @@ pub fn reject_device(user: AuthenticatedUser, request: Json<DeviceButtonAction>,
 }
 
 #[post("/device/force-renew", format = "json", data = "<request>")]
-pub fn force_renew_device(request: Json<DeviceButtonAction>, db: State<DB>, checkin_api: State<CheckinAPI>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn force_renew_device(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceButtonAction>, db: State<DB>, checkin_api: State<CheckinAPI>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
             match checkin_api.delete_user(&request.username) {
                 Ok(_) => {
-                    device.update(
+                    device.clone().update(
                         db.clone(),
                         None,
                         doc! { "$set": {
@@ -322,6 +881,7 @@ pub fn force_renew_device(request: Json<DeviceButtonAction>, db: State<DB>, chec
                         } },
                         None
                     )?;
+                    record_event(&db, &ip, &user.username, AuditAction::ForceRenew, Some(&device), None, None)?;
                     json!({
                         "success": true,
                     })
@@ -346,20 +906,22 @@ pub fn force_renew_device(request: Json<DeviceButtonAction>, db: State<DB>, chec
 }
 
 #[post("/device/delete", format = "json", data = "<request>")]
-pub fn delete_device(request: Json<DeviceButtonAction>, db: State<DB>, checkin_api: State<CheckinAPI>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn delete_device(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceButtonAction>, db: State<DB>, checkin_api: State<CheckinAPI>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
             if device.credentials_created {
                 // Delete this device's checkin2 account if one exists
-                if let Err(err) = checkin_api.delete_user(&request.username) {
-                    return Ok(json!({
-                        "success": false,
-                        "error": "Failed to delete device's checkin2 account",
-                        "details": format!("{:?}", err),
-                    }));
+                if let Err(response) = delete_checkin_account(&checkin_api, &request.username) {
+                    return Ok(response);
                 }
             }
             device.delete(db.clone())?;
+            record_event(&db, &ip, &user.username, AuditAction::Delete, Some(&device), None, None)?;
+            // Ends the device's long-poll so that it finds out it was deleted
+            updates.notify(&request.username);
             json!({
                 "success": true,
             })
@@ -380,10 +942,13 @@ pub struct DeviceTagAction {
     tag: String,
 }
 #[post("/device/set-tag", format = "json", data = "<request>")]
-pub fn set_tag(request: Json<DeviceTagAction>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn set_tag(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceTagAction>, db: State<DB>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::TagOperator) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
-            device.update(
+            device.clone().update(
                 db.clone(),
                 None,
                 doc! { "$set": {
@@ -391,6 +956,8 @@ pub fn set_tag(request: Json<DeviceTagAction>, db: State<DB>) -> Result<JsonValu
                 } },
                 None
             )?;
+            record_event(&db, &ip, &user.username, AuditAction::SetTag, Some(&device), device.current_tag.clone(), Some(request.tag.clone()))?;
+            updates.notify(&request.username);
             json!({
                 "success": true,
             })
@@ -411,10 +978,13 @@ pub struct DeviceRenameAction {
     name: String,
 }
 #[post("/device/rename", format = "json", data = "<request>")]
-pub fn rename_device(request: Json<DeviceRenameAction>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+pub fn rename_device(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceRenameAction>, db: State<DB>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
     let response = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
         Some(device) => {
-            device.update(
+            device.clone().update(
                 db.clone(),
                 None,
                 doc! { "$set": {
@@ -422,6 +992,7 @@ pub fn rename_device(request: Json<DeviceRenameAction>, db: State<DB>) -> Result
                 } },
                 None
             )?;
+            record_event(&db, &ip, &user.username, AuditAction::Rename, Some(&device), Some(device.friendly_name.clone()), Some(request.name.clone()))?;
             json!({
                 "success": true,
             })
@@ -435,3 +1006,301 @@ pub fn rename_device(request: Json<DeviceRenameAction>, db: State<DB>) -> Result
     };
     Ok(response)
 }
+
+// Only the fields filled in on the bulk edit form are changed
+#[derive(Deserialize)]
+pub struct SettingsChanges {
+    brightness: Option<u8>,
+    scroll_speed: Option<u32>,
+    buzzer_enabled: Option<bool>,
+    tone_theme: Option<ToneTheme>,
+    // 0 goes back to the device's own interval
+    poll_interval: Option<u32>,
+    // An empty message clears it
+    idle_message: Option<String>,
+}
+
+impl SettingsChanges {
+    fn apply(&self, settings: &DeviceSettings) -> DeviceSettings {
+        let mut settings = settings.clone();
+        if let Some(brightness) = self.brightness {
+            settings.brightness = brightness;
+        }
+        if let Some(scroll_speed) = self.scroll_speed {
+            settings.scroll_speed = scroll_speed;
+        }
+        if let Some(buzzer_enabled) = self.buzzer_enabled {
+            settings.buzzer_enabled = buzzer_enabled;
+        }
+        if let Some(tone_theme) = self.tone_theme {
+            settings.tone_theme = tone_theme;
+        }
+        if let Some(poll_interval) = self.poll_interval {
+            settings.poll_interval = Some(poll_interval).filter(|&seconds| seconds != 0);
+        }
+        if let Some(idle_message) = &self.idle_message {
+            settings.idle_message = Some(idle_message.trim().to_owned()).filter(|message| !message.is_empty());
+        }
+        settings
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeviceSettingsAction {
+    usernames: Vec<String>,
+    settings: SettingsChanges,
+}
+#[post("/device/settings", format = "json", data = "<request>")]
+pub fn change_settings(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceSettingsAction>, db: State<DB>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
+    if request.usernames.is_empty() {
+        return Ok(json!({
+            "success": false,
+            "error": "No devices selected",
+        }));
+    }
+    // Check everything before changing anything so that a bad device doesn't leave the others half updated
+    let mut changes = Vec::new();
+    for username in request.usernames.iter() {
+        let device = match Device::find_one(db.clone(), Some(doc! { "username": username }), None)? {
+            Some(device) => device,
+            None => return Ok(json!({
+                "success": false,
+                "error": "Device not found",
+                "details": username,
+            })),
+        };
+        let settings = request.settings.apply(&device.settings);
+        if let Err(err) = settings.validate() {
+            return Ok(json!({
+                "success": false,
+                "error": "Invalid settings",
+                "details": err,
+            }));
+        }
+        changes.push((device, settings));
+    }
+    for (device, settings) in changes {
+        if settings == device.settings {
+            continue;
+        }
+        device.clone().update(
+            db.clone(),
+            None,
+            doc! { "$set": {
+                "settings": mongodb::to_bson(&settings)?,
+            } },
+            None
+        )?;
+        record_event(
+            &db,
+            &ip,
+            &user.username,
+            AuditAction::ChangeSettings,
+            Some(&device),
+            serde_json::to_string(&device.settings).ok(),
+            serde_json::to_string(&settings).ok()
+        )?;
+        updates.notify(&device.username);
+    }
+    Ok(json!({
+        "success": true,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DeviceCommandAction {
+    username: String,
+    command: CommandKind,
+    message: Option<String>,
+}
+#[post("/device/command", format = "json", data = "<request>")]
+pub fn send_command(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<DeviceCommandAction>, db: State<DB>, checkin_api: State<CheckinAPI>, updates: State<DeviceUpdates>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, request.command.required_role()) {
+        return Ok(response);
+    }
+    let device = match Device::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
+        Some(device) => device,
+        None => return Ok(json!({
+            "success": false,
+            "error": "Device not found",
+        })),
+    };
+    if !device.authorized {
+        return Ok(json!({
+            "success": false,
+            "error": "Device is not authorized",
+        }));
+    }
+    let message = match request.command {
+        // Scrolled so that whoever is looking can confirm they found the right one
+        CommandKind::Identify => Some(device.friendly_name.clone()),
+        CommandKind::ShowMessage => match request.message.as_ref().map(|message| message.trim()).filter(|message| !message.is_empty()) {
+            Some(message) => Some(message.to_owned()),
+            None => return Ok(json!({
+                "success": false,
+                "error": "A message is required",
+            })),
+        },
+        CommandKind::Reboot | CommandKind::RotateKey => None,
+        CommandKind::RefetchCredentials => {
+            // Same as a forced renewal except that the device starts over right away instead of on its next restart
+            if device.credentials_created {
+                if let Err(err) = checkin_api.delete_user(&device.username) {
+                    return Ok(json!({
+                        "success": false,
+                        "error": "Failed to delete user account",
+                        "details": format!("{:?}", err),
+                    }));
+                }
+                device.clone().update(db.clone(), None, doc! { "$set": { "credentials_created": false } }, None)?;
+            }
+            None
+        },
+    };
+
+    let mut command = DeviceCommand {
+        id: Some(ObjectId::new().expect("Could not generate command ID")),
+
+        device: device.username.clone(),
+        command: request.command,
+        message,
+
+        issued_by: user.username.clone(),
+        issued_at: Utc::now(),
+        delivered_at: None,
+        acknowledged_at: None,
+        success: None,
+        details: None,
+    };
+    command.save(db.clone(), None)?;
+    record_event(&db, &ip, &user.username, AuditAction::SendCommand, Some(&device), None, Some(match &command.message {
+        Some(message) => format!("{}: {}", command.command.as_str(), message),
+        None => command.command.as_str().to_owned(),
+    }))?;
+    updates.notify(&device.username);
+
+    Ok(json!({
+        "success": true,
+        "id": command.id.as_ref().map(ObjectId::to_hex),
+    }))
+}
+
+// Polled by the UI after sending a command to show when the device gets it
+#[get("/device/command-status?<id>")]
+pub fn command_status(_user: AuthenticatedUser, id: Option<String>, db: State<DB>) -> Result<JsonValue, mongodb::error::Error> {
+    let id = match id.map(|id| ObjectId::with_string(&id)) {
+        Some(Ok(id)) => id,
+        _ => return Ok(json!({
+            "success": false,
+            "error": "Invalid command ID",
+        })),
+    };
+    let response = match DeviceCommand::find_one(db.clone(), Some(doc! { "_id": id }), None)? {
+        Some(command) => json!({
+            "success": true,
+            "status": command.status(),
+            "details": command.details,
+        }),
+        None => json!({
+            "success": false,
+            "error": "Command not found",
+        }),
+    };
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct UserRoleAction {
+    username: String,
+    role: Role,
+}
+#[post("/user/set-role", format = "json", data = "<request>")]
+pub fn set_role(user: AuthenticatedUser, _csrf: CsrfVerified, request: Json<UserRoleAction>, db: State<DB>, ip: IP) -> Result<JsonValue, mongodb::error::Error> {
+    if let Err(response) = require_role(&user, Role::DeviceAdmin) {
+        return Ok(response);
+    }
+    if request.username == user.username {
+        return Ok(json!({
+            "success": false,
+            "error": "You can't change your own role",
+        }));
+    }
+    let target = match User::find_one(db.clone(), Some(doc! { "username": &request.username }), None)? {
+        Some(target) => target,
+        None => return Ok(json!({
+            "success": false,
+            "error": "User not found",
+        })),
+    };
+    let old_role = target.role;
+    target.update(
+        db.clone(),
+        None,
+        doc! { "$set": {
+            "role": request.role.as_str(),
+        } },
+        None
+    )?;
+    record_event(
+        &db,
+        &ip,
+        &user.username,
+        AuditAction::SetRole,
+        None,
+        Some(format!("{}: {}", request.username, old_role.as_str())),
+        Some(format!("{}: {}", request.username, request.role.as_str()))
+    )?;
+    Ok(json!({
+        "success": true,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_cache_never_forgets_unexpired_nonces() {
+        let cache = NonceCache::new(2, 2);
+        let now = 1_000_000;
+        assert_eq!(cache.check_at(now, "key", now, "a"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(now, "key", now, "a"), NonceCheck::Replayed);
+        assert_eq!(cache.check_at(now, "key", now - 2 * NonceCache::MAX_AGE, "b"), NonceCheck::Stale);
+        // Timestamps this far out would overflow if MAX_AGE was just added to them
+        assert_eq!(cache.check_at(now, "key", u64::max_value(), "b"), NonceCheck::Stale);
+        assert_eq!(cache.check_at(now + 1, "key", now, "b"), NonceCheck::Fresh);
+
+        // Full of nonces that could still be replayed, so new requests are turned away instead of evicting them
+        assert_eq!(cache.check_at(now + 2, "other key", now, "c"), NonceCheck::Full);
+        assert_eq!(cache.check_at(now + 3, "key", now, "a"), NonceCheck::Replayed);
+
+        // Once they're old enough that their requests would be rejected as stale there's room again
+        let later = now + 2 * NonceCache::MAX_AGE + 2;
+        assert_eq!(cache.check_at(later, "key", later, "c"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(later, "key", later, "d"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(later, "other key", later, "e"), NonceCheck::Full);
+
+        let cache = NonceCache::new(2, 2);
+        assert_eq!(cache.check_at(u64::max_value(), "key", u64::max_value(), "a"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(u64::max_value(), "key", u64::max_value(), "a"), NonceCheck::Replayed);
+    }
+
+    #[test]
+    fn nonce_cache_limits_each_device() {
+        let cache = NonceCache::new(3, 2);
+        let now = 1_000_000;
+        assert_eq!(cache.check_at(now, "leaked key", now, "a"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(now, "leaked key", now, "b"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(now, "leaked key", now, "c"), NonceCheck::DeviceFull);
+        // Other devices still have room
+        assert_eq!(cache.check_at(now, "key", now, "c"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(now, "key", now, "d"), NonceCheck::Full);
+
+        let later = now + 2 * NonceCache::MAX_AGE + 1;
+        assert_eq!(cache.check_at(later, "leaked key", later, "e"), NonceCheck::Fresh);
+        assert_eq!(cache.check_at(later, "key", later, "f"), NonceCheck::Fresh);
+    }
+}
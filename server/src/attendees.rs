@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use serde::{ Serialize, Deserialize };
+use chrono::Utc;
+use hackgt_nfc::api::CheckinAPI;
+use crate::signing::ServerKey;
+
+#[derive(Serialize)]
+pub struct Attendee {
+    id: String,
+    checked_in: bool,
+}
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    tag: &'a str,
+    generated: String,
+    attendees: Vec<Attendee>,
+}
+
+/// A snapshot serialized to JSON along with the server's signature over those exact bytes
+#[derive(Clone)]
+pub struct SignedSnapshot {
+    pub snapshot: String,
+    pub signature: String,
+}
+
+/// Caches signed attendee snapshots per tag so that every device refreshing doesn't each page through all of checkin2
+pub struct AttendeeSnapshots {
+    snapshots: Mutex<HashMap<String, (Instant, SignedSnapshot)>>,
+}
+
+impl AttendeeSnapshots {
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        Self {
+            snapshots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, tag: &str, checkin_api: &CheckinAPI, key: &ServerKey) -> Result<SignedSnapshot, String> {
+        if let Some((created, snapshot)) = self.snapshots.lock().unwrap().get(tag) {
+            if created.elapsed() < AttendeeSnapshots::MAX_AGE {
+                return Ok(snapshot.clone());
+            }
+        }
+        let snapshot = Snapshot {
+            tag,
+            generated: Utc::now().to_rfc3339(),
+            attendees: fetch_attendees(checkin_api, tag)?,
+        };
+        let snapshot = serde_json::to_string(&snapshot).expect("Could not serialize attendee snapshot");
+        let signed = SignedSnapshot {
+            signature: key.sign(snapshot.as_bytes()),
+            snapshot,
+        };
+        self.snapshots.lock().unwrap().insert(tag.to_owned(), (Instant::now(), signed.clone()));
+        Ok(signed)
+    }
+}
+
+// Pages through every user in checkin2 and pulls out their state for a single tag
+fn fetch_attendees(checkin_api: &CheckinAPI, tag: &str) -> Result<Vec<Attendee>, String> {
+    #[derive(Serialize)]
+    struct Variables<'a> {
+        pagination_token: Option<&'a str>,
+        n: u32,
+    }
+    #[derive(Serialize)]
+    struct Query<'a> {
+        query: &'static str,
+        variables: Variables<'a>,
+    }
+    #[derive(Deserialize)]
+    struct Response {
+        data: Option<Data>,
+        errors: Option<serde_json::Value>,
+    }
+    #[derive(Deserialize)]
+    struct Data {
+        users: Vec<UserAndTags>,
+    }
+    #[derive(Deserialize)]
+    struct UserAndTags {
+        pagination_token: String,
+        user: User,
+        tags: Vec<TagState>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct TagState {
+        tag: Tag,
+        checked_in: bool,
+    }
+    #[derive(Deserialize)]
+    struct Tag {
+        name: String,
+    }
+    const QUERY: &'static str = "query($pagination_token: ID, $n: Int!) {
+        users(pagination_token: $pagination_token, n: $n) {
+            pagination_token
+            user { id }
+            tags { tag { name } checked_in }
+        }
+    }";
+    const PAGE_SIZE: u32 = 500;
+
+    let base_url = std::env::var("CHECKIN_URL").unwrap_or("https://checkin.hack.gt".to_owned());
+    let url = format!("{}/graphql", base_url.trim_end_matches("/"));
+    let client = reqwest::Client::new();
+
+    let mut attendees = Vec::new();
+    let mut pagination_token: Option<String> = None;
+    loop {
+        let query = Query {
+            query: QUERY,
+            variables: Variables {
+                pagination_token: pagination_token.as_ref().map(String::as_str),
+                n: PAGE_SIZE,
+            },
+        };
+        let response: Response = client.post(&url)
+            .header(reqwest::header::COOKIE, format!("auth={}", checkin_api.auth_token()))
+            .json(&query)
+            .send()
+            .and_then(|mut response| response.json())
+            .map_err(|err| format!("{:?}", err))?;
+        let users = match response.data {
+            Some(data) => data.users,
+            None => return Err(format!("GraphQL error: {:?}", response.errors)),
+        };
+        let page_length = users.len();
+        for user in users {
+            pagination_token = Some(user.pagination_token);
+            attendees.push(Attendee {
+                checked_in: user.tags.iter().any(|state| state.tag.name == tag && state.checked_in),
+                id: user.user.id,
+            });
+        }
+        if page_length < PAGE_SIZE as usize {
+            break;
+        }
+    }
+    Ok(attendees)
+}
@@ -4,17 +4,26 @@ use rocket::request::{ self, Request, FromRequest, Form };
 use rocket::response::Redirect;
 use rocket_contrib::templates::Template;
 use wither::model::Model;
+use chrono::{ Duration, Utc };
 use hackgt_nfc::api::CheckinAPI;
 use crate::DB;
-use crate::models::User;
+use crate::api::IP;
+use crate::csrf::{ self, CsrfForm };
+use crate::models::{ User, Role, Session };
 
-pub struct AuthenticatedUser(User);
+/// How long a login lasts before the user has to sign in again
+const SESSION_LENGTH_HOURS: i64 = 12;
+
+pub struct AuthenticatedUser {
+    user: User,
+    pub session: Session,
+}
 
 impl std::ops::Deref for AuthenticatedUser {
     type Target = User;
     #[inline(always)]
     fn deref(&self) -> &User {
-        &self.0
+        &self.user
     }
 }
 
@@ -22,6 +31,7 @@ impl std::ops::Deref for AuthenticatedUser {
 pub enum AuthenticatedUserError {
     Missing,
     Invalid,
+    Expired,
     DBError(wither::mongodb::error::Error),
 }
 
@@ -30,16 +40,27 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
         let cookies = request.cookies();
-		let token = cookies.get("auth").map(|cookie| cookie.value());
-        match token {
+		let session_id = cookies.get("auth").map(|cookie| cookie.value());
+        match session_id {
             Some("") | None => {
                 Outcome::Failure((Status::Unauthorized, AuthenticatedUserError::Missing))
             },
-            Some(token) => {
+            Some(session_id) => {
                 let db = request.guard::<State<DB>>().unwrap();
-                let user = User::find_one(db.clone(), Some(doc!{ "auth_token": token }), None);
+                let session = match Session::find_one(db.clone(), Some(doc!{ "session_id": session_id }), None) {
+                    Ok(Some(session)) => session,
+                    Ok(None) => return Outcome::Failure((Status::Unauthorized, AuthenticatedUserError::Invalid)),
+                    Err(err) => return Outcome::Failure((Status::InternalServerError, AuthenticatedUserError::DBError(err))),
+                };
+                if session.expires < Utc::now() {
+                    if let Err(err) = session.delete(db.clone()) {
+                        return Outcome::Failure((Status::InternalServerError, AuthenticatedUserError::DBError(err)));
+                    }
+                    return Outcome::Failure((Status::Unauthorized, AuthenticatedUserError::Expired));
+                }
+                let user = User::find_one(db.clone(), Some(doc!{ "username": &session.username }), None);
                 match user {
-                    Ok(Some(user)) => Outcome::Success(AuthenticatedUser(user)),
+                    Ok(Some(user)) => Outcome::Success(AuthenticatedUser { user, session }),
                     Ok(None) => Outcome::Failure((Status::Unauthorized, AuthenticatedUserError::Invalid)),
                     Err(err) => Outcome::Failure((Status::InternalServerError, AuthenticatedUserError::DBError(err))),
                 }
@@ -49,8 +70,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
 }
 
 #[get("/login")]
-pub fn login() -> Template {
-    Template::render("login", &json!({}))
+pub fn login(mut cookies: Cookies) -> Template {
+    Template::render("login", &json!({
+        "csrf_token": csrf::token(&mut cookies),
+    }))
 }
 
 fn create_auth_cookie(value: String) -> Cookie<'static> {
@@ -61,36 +84,119 @@ fn create_auth_cookie(value: String) -> Cookie<'static> {
         .finish()
 }
 
-#[get("/logout")]
-pub fn logout(mut cookies: Cookies) -> Redirect {
+#[post("/logout", data = "<body>")]
+pub fn logout(user: Option<AuthenticatedUser>, body: Form<CsrfForm>, mut cookies: Cookies, db: State<DB>) -> Redirect {
+    // Otherwise any page could log people out with an image tag
+    if !csrf::verify(&cookies, &body.csrf_token) {
+        return Redirect::to("/");
+    }
+    if let Some(user) = user {
+        // Make sure the old cookie can't be reused even if someone copied it
+        if let Err(err) = user.session.delete(db.clone()) {
+            eprintln!("Failed to delete session: {:?}", err);
+        }
+    }
     cookies.add(create_auth_cookie(String::new()));
     Redirect::to("/auth/login")
 }
 
+#[post("/logout-everywhere", data = "<body>")]
+pub fn logout_everywhere(user: AuthenticatedUser, body: Form<CsrfForm>, mut cookies: Cookies, db: State<DB>) -> Result<Redirect, wither::mongodb::error::Error> {
+    if !csrf::verify(&cookies, &body.csrf_token) {
+        return Ok(Redirect::to("/"));
+    }
+    Session::collection(db.clone()).delete_many(doc!{ "username": &user.username }, None)?;
+    cookies.add(create_auth_cookie(String::new()));
+    Ok(Redirect::to("/auth/login"))
+}
+
+// Users listed in ADMIN_USERNAMES (comma separated) are always device admins so that someone can hand out roles
+fn is_admin_username(username: &str) -> bool {
+    let admins = std::env::var("ADMIN_USERNAMES").unwrap_or_default();
+    admins.split(",").any(|admin| admin.trim() == username)
+}
+
 #[derive(FromForm, Debug)]
 pub struct LoginInfo {
+    csrf_token: String,
     username: String,
     password: String,
 }
 #[post("/login", data = "<body>")]
-pub fn process_login(body: Form<LoginInfo>, mut cookies: Cookies, db: State<DB>) -> Redirect {
-    match CheckinAPI::login(&body.username, &body.password) {
-        Ok(api) => {
-            let token = api.auth_token();
+pub fn process_login(body: Form<LoginInfo>, mut cookies: Cookies, db: State<DB>, ip: IP) -> Result<Redirect, wither::mongodb::error::Error> {
+    if !csrf::verify(&cookies, &body.csrf_token) {
+        return Ok(Redirect::to("/auth/login"));
+    }
+    // We only use checkin2 to verify the password and never hand out its token
+    if let Err(err) = CheckinAPI::login(&body.username, &body.password) {
+        eprintln!("{:?}", err);
+        return Ok(Redirect::to("/auth/login"));
+    }
+
+    let now = Utc::now();
+    match User::find_one(db.clone(), Some(doc!{ "username": &body.username }), None)? {
+        Some(user) => {
+            let role = if is_admin_username(&body.username) { Role::DeviceAdmin } else { user.role };
+            user.update(
+                db.clone(),
+                None,
+                doc! { "$set": {
+                    "role": role.as_str(),
+                    "last_login": now.to_rfc3339(),
+                } },
+                None
+            )?;
+        },
+        None => {
             let mut user = User {
                 id: None,
                 username: body.username.clone(),
-                auth_token: token.to_owned(),
+                role: if is_admin_username(&body.username) { Role::DeviceAdmin } else { Role::default() },
+                last_login: Some(now),
             };
-            user.save(db.clone(), None).unwrap();
-            cookies.add(create_auth_cookie(token.to_owned()));
-            Redirect::to("/")
+            user.save(db.clone(), None)?;
         },
-        Err(err) => {
-            eprintln!("{:?}", err);
-            Redirect::to("/auth/login")
+    }
+
+    let mut session = Session {
+        id: None,
+
+        session_id: hex::encode(&rand::random::<[u8; 32]>()[..]),
+        username: body.username.clone(),
+        ip_address: ip.as_str().to_owned(),
+
+        created: now,
+        expires: now + Duration::hours(SESSION_LENGTH_HOURS),
+    };
+    session.save(db.clone(), None)?;
+    cookies.add(create_auth_cookie(session.session_id));
+    Ok(Redirect::to("/"))
+}
+
+/// Deletes expired sessions and collapses the per-login User documents that older versions created
+pub fn clean_up(db: &DB) -> Result<(), wither::mongodb::error::Error> {
+    let now = Utc::now();
+    for session in Session::find(db.clone(), None, None).unwrap_or(Vec::new()) {
+        if session.expires < now {
+            session.delete(db.clone())?;
+        }
+    }
+
+    let mut seen: Vec<String> = Vec::new();
+    let mut users = User::find(db.clone(), None, None).unwrap_or(Vec::new());
+    // Keep the most privileged copy of each user
+    users.sort_by(|a, b| b.role.cmp(&a.role));
+    for user in users {
+        if seen.contains(&user.username) {
+            user.delete(db.clone())?;
+        }
+        else {
+            seen.push(user.username.clone());
+            // Old documents stored the user's checkin2 token which we no longer want lying around
+            user.update(db.clone(), None, doc! { "$unset": { "auth_token": "" } }, None)?;
         }
     }
+    Ok(())
 }
 
 #[catch(401)]
@@ -0,0 +1,72 @@
+use rocket::Outcome;
+use rocket::http::{ Cookie, Cookies, SameSite, Status };
+use rocket::request::{ self, Request, FromRequest };
+
+const COOKIE_NAME: &'static str = "csrf";
+const HEADER_NAME: &'static str = "X-CSRF-Token";
+
+/// Returns this browser's CSRF token, issuing a new one if it doesn't have one yet
+pub fn token(cookies: &mut Cookies) -> String {
+    if let Some(cookie) = cookies.get(COOKIE_NAME) {
+        if !cookie.value().is_empty() {
+            return cookie.value().to_owned();
+        }
+    }
+    let token = hex::encode(&rand::random::<[u8; 32]>()[..]);
+    cookies.add(Cookie::build(COOKIE_NAME, token.clone())
+        .path("/")
+        .secure(!cfg!(debug_assertions)) // Will be secure-only when built with --release
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish()
+    );
+    token
+}
+
+/// Checks a submitted token against the one stored in the browser's cookie
+pub fn verify(cookies: &Cookies, submitted: &str) -> bool {
+    match cookies.get(COOKIE_NAME) {
+        Some(cookie) if !submitted.is_empty() => constant_time_eq(cookie.value().as_bytes(), submitted.as_bytes()),
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |result, (x, y)| result | (x ^ y)) == 0
+}
+
+// Plain HTML forms send the token as a hidden field instead of a header
+#[derive(FromForm)]
+pub struct CsrfForm {
+    pub csrf_token: String,
+}
+
+/// Request guard for JSON requests made by the manager UI, which send the token in the X-CSRF-Token header
+pub struct CsrfVerified;
+
+#[derive(Debug)]
+pub enum CsrfError {
+    Missing,
+    Invalid,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfVerified {
+    type Error = CsrfError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one(HEADER_NAME) {
+            Some(token) => {
+                if verify(&request.cookies(), token) {
+                    Outcome::Success(CsrfVerified)
+                }
+                else {
+                    Outcome::Failure((Status::Forbidden, CsrfError::Invalid))
+                }
+            },
+            None => Outcome::Failure((Status::Forbidden, CsrfError::Missing)),
+        }
+    }
+}
@@ -4,25 +4,34 @@
 #[macro_use] extern crate mongodb;
 #[macro_use] extern crate wither_derive;
 
+use std::collections::HashMap;
 use rocket::State;
+use rocket::http::{ Cookies, Status };
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
 
 use serde::Serialize;
 use mongodb::{ ThreadedClient, doc };
+use mongodb::coll::options::FindOptions;
 use wither::model::Model;
 use hackgt_nfc::api::CheckinAPI;
 
 pub type DB = std::sync::Arc<mongodb::db::DatabaseInner>;
 
 mod models;
-use models::Device;
+use models::{ Device, ToneTheme, DeviceCommand, User, Role, AuditEvent, AuditAction };
 mod api;
 mod auth;
 use auth::AuthenticatedUser;
+mod csrf;
+mod alerts;
+mod updates;
+mod signing;
+mod attendees;
+mod releases;
 
 #[get("/")]
-fn index(user: AuthenticatedUser, db: State<DB>, checkin_api: State<CheckinAPI>) -> Template {
+fn index(user: AuthenticatedUser, mut cookies: Cookies, db: State<DB>, checkin_api: State<CheckinAPI>) -> Template {
 	let devices = match Device::find(db.clone(), None, None) {
 		Ok(result) => result,
 		// Driver returns an error if no documents are found
@@ -38,10 +47,29 @@ fn index(user: AuthenticatedUser, db: State<DB>, checkin_api: State<CheckinAPI>)
 	}
 	#[derive(Serialize)]
 	struct DeviceWithTag {
+		#[serde(flatten)]
 		device: Device,
 		tags: Vec<Tag>,
+		online: bool,
+		last_seen_at: Option<String>,
+		last_command: Option<String>,
 	}
+	// Newest command for every device in one query instead of one per device
+	let pipeline = vec![
+		doc! { "$sort": { "issued_at": -1 } },
+		doc! { "$group": { "_id": "$device", "latest": { "$first": "$$ROOT" } } },
+	];
+	let latest_commands: HashMap<String, DeviceCommand> = DeviceCommand::collection(db.clone()).aggregate(pipeline, None)
+		.map(|groups| groups
+			.filter_map(|group| group.ok()?.remove("latest"))
+			.filter_map(|latest| mongodb::from_bson::<DeviceCommand>(latest).ok())
+			.map(|command| (command.device.clone(), command))
+			.collect())
+		.unwrap_or_default();
 	let devices_with_tag: Vec<DeviceWithTag> = devices.into_iter().map(|device| DeviceWithTag {
+		online: device.is_online(),
+		last_command: latest_commands.get(&device.username).map(|command| format!("{} {}", command.command.as_str(), command.status())),
+		last_seen_at: device.last_seen.map(|last_seen| last_seen.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
 		device: device.clone(),
 		tags: tags.iter().map(|tag| Tag {
 			name: tag.to_string(),
@@ -49,12 +77,154 @@ fn index(user: AuthenticatedUser, db: State<DB>, checkin_api: State<CheckinAPI>)
 		}).collect(),
 	}).collect();
 
+	// Only device admins get to see and assign everyone's roles
+	let mut users: Vec<User> = Vec::new();
+	if user.has_role(Role::DeviceAdmin) {
+		users = User::find(db.clone(), None, None).unwrap_or(Vec::new());
+		users.sort_by(|a, b| a.username.cmp(&b.username));
+	}
+	#[derive(Serialize)]
+	struct UserWithRole {
+		username: String,
+		is_self: bool,
+		roles: Vec<RoleOption>,
+	}
+	#[derive(Serialize)]
+	struct RoleOption {
+		name: &'static str,
+		selected: bool,
+	}
+	let users_with_role: Vec<UserWithRole> = users.into_iter().map(|other| UserWithRole {
+		is_self: other.username == user.username,
+		roles: [Role::Viewer, Role::TagOperator, Role::DeviceAdmin].iter().map(|role| RoleOption {
+			name: role.as_str(),
+			selected: *role == other.role,
+		}).collect(),
+		username: other.username,
+	}).collect();
+
 	Template::render("index", &json!({
 		"devices": devices_with_tag,
+		"users": users_with_role,
 		"username": user.username,
+		"role": user.role.as_str(),
+		"can_set_tags": user.has_role(Role::TagOperator),
+		"can_manage_devices": user.has_role(Role::DeviceAdmin),
+		"tone_themes": ToneTheme::ALL.iter().map(ToneTheme::as_str).collect::<Vec<_>>(),
+		"csrf_token": csrf::token(&mut cookies),
 	}))
 }
 
+#[get("/audit?<actor>&<device>&<action>")]
+fn audit_log(user: AuthenticatedUser, actor: Option<String>, device: Option<String>, action: Option<String>, db: State<DB>) -> Result<Template, Status> {
+	if !user.has_role(Role::DeviceAdmin) {
+		return Err(Status::Forbidden);
+	}
+	// Submitting the filter form sends empty strings for filters that weren't filled in
+	let actor = actor.filter(|actor| !actor.is_empty());
+	let device = device.filter(|device| !device.is_empty());
+	let action = action.filter(|action| !action.is_empty());
+
+	let mut filter = match &device {
+		// Devices can be looked up by either their username or the name they had at the time
+		Some(device) => doc! { "$or": [
+			{ "device": device.clone() },
+			{ "device_name": device.clone() }
+		] },
+		None => doc! {},
+	};
+	if let Some(actor) = &actor {
+		filter.insert("actor", actor.clone());
+	}
+	if let Some(action) = &action {
+		filter.insert("action", action.clone());
+	}
+	let mut options = FindOptions::new();
+	options.sort = Some(doc! { "time": -1 });
+	options.limit = Some(500);
+	let events = match AuditEvent::find(db.clone(), Some(filter), Some(options)) {
+		Ok(result) => result,
+		// Driver returns an error if no documents are found
+		Err(_) => Vec::new(),
+	};
+
+	#[derive(Serialize)]
+	struct EventView {
+		time: String,
+		action: &'static str,
+		actor: String,
+		device: Option<String>,
+		device_name: Option<String>,
+		old_value: Option<String>,
+		new_value: Option<String>,
+		ip_address: String,
+	}
+	let events: Vec<EventView> = events.into_iter().map(|event| EventView {
+		time: event.time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+		action: event.action.as_str(),
+		actor: event.actor,
+		device: event.device,
+		device_name: event.device_name,
+		old_value: event.old_value,
+		new_value: event.new_value,
+		ip_address: event.ip_address,
+	}).collect();
+
+	#[derive(Serialize)]
+	struct ActionOption {
+		name: &'static str,
+		selected: bool,
+	}
+	let actions: Vec<ActionOption> = AuditAction::ALL.iter().map(|option| ActionOption {
+		name: option.as_str(),
+		selected: action.as_ref().map(|action| action == option.as_str()).unwrap_or(false),
+	}).collect();
+
+	Ok(Template::render("audit", &json!({
+		"events": events,
+		"actions": actions,
+		"actor": actor,
+		"device": device,
+		"username": user.username,
+	})))
+}
+
+fn api_routes() -> Vec<rocket::Route> {
+	routes![
+		api::initialize,
+		api::create_credentials,
+		api::get_tag,
+		api::heartbeat,
+		api::download_release,
+		api::wait_for_updates,
+		api::get_attendees,
+		api::authorize_device,
+		api::reject_device,
+		api::force_renew_device,
+		api::delete_device,
+		api::rename_device,
+		api::set_tag,
+		api::change_settings,
+		api::send_command,
+		api::command_status,
+		api::acknowledge_command,
+		api::rotate_key,
+		api::set_role,
+	]
+}
+
+// Creates the indexes declared on each model, including the unique ones that keep usernames and device keys from being duplicated.
+// Older versions created a User document per login so those are collapsed first or the username index can't be built.
+fn prepare_database(db: &DB) -> Result<(), mongodb::error::Error> {
+	auth::clean_up(db)?;
+	Device::sync(db.clone())?;
+	User::sync(db.clone())?;
+	models::Session::sync(db.clone())?;
+	AuditEvent::sync(db.clone())?;
+	DeviceCommand::sync(db.clone())?;
+	Ok(())
+}
+
 fn main() {
 	println!("Logging into HackGT Check-In API...");
 	let checkin_api = match std::env::var("CHECKIN_TOKEN") {
@@ -69,32 +239,169 @@ fn main() {
 	let mongo_url = std::env::var("MONGO_URL").unwrap_or("mongodb://localhost".to_owned());
 	let db_name = std::env::var("MONGO_DB").unwrap_or("checkin-embedded".to_owned());
 	let db = mongodb::Client::with_uri(&mongo_url).expect("Failed to connect to the MongoDB server").db(&db_name);
+	prepare_database(&db).expect("Failed to prepare the database");
+
+	// Clean up expired sessions every hour
+	let cleanup_db = db.clone();
+	std::thread::spawn(move || {
+		loop {
+			std::thread::sleep(std::time::Duration::from_secs(60 * 60));
+			if let Err(err) = auth::clean_up(&cleanup_db) {
+				eprintln!("Session cleanup failed: {:?}", err);
+			}
+		}
+	});
+	alerts::OfflineMonitor::from_env(db.clone()).start();
+	let server_key = signing::ServerKey::load();
+	// Set as manager_key on devices that shouldn't have to trust whatever answers the first time
+	println!("Manager key: {}", server_key.public_key());
 
 	rocket::ignite()
 		.attach(Template::fairing())
-		.mount("/", routes![index])
+		.attach(signing::ResponseSigner)
+		.mount("/", routes![index, audit_log])
 		.mount("/auth", routes![
 			auth::login,
 			auth::process_login,
 			auth::logout,
+			auth::logout_everywhere,
 		])
-		.mount("/api", routes![
-			api::initialize,
-			api::create_credentials,
-			api::get_tag,
-			api::authorize_device,
-			api::reject_device,
-			api::force_renew_device,
-			api::delete_device,
-			api::rename_device,
-			api::set_tag,
-		])
+		.mount("/api", api_routes())
 		.mount("/css", StaticFiles::from("src/ui/css"))
 		.mount("/js", StaticFiles::from("src/ui/js"))
 		.register(catchers![
 			auth::unauthorized_redirect
 		])
 		.manage(db)
+		.manage(api::NonceCache::new(10_000, 500))
+		.manage(updates::DeviceUpdates::new())
+		.manage(server_key)
+		.manage(attendees::AttendeeSnapshots::new())
+		.manage(releases::Releases::new())
 		.manage(checkin_api)
 		.launch();
 }
+
+#[cfg(test)]
+mod tests {
+	use rocket::local::Client;
+	use rocket::http::{ ContentType, Status };
+
+	#[test]
+	fn device_routes_require_login() {
+		// No database is managed here, so a route that gets past its guards without a login fails with a 500 instead
+		let client = Client::new(rocket::ignite().mount("/api", super::api_routes())).unwrap();
+		let routes: Vec<(rocket::http::Method, String)> = client.rocket().routes()
+			.filter(|route| route.uri.path().starts_with("/api/device/"))
+			.map(|route| (route.method, route.uri.path().to_owned()))
+			.collect();
+		assert!(!routes.is_empty());
+
+		let unguarded: Vec<&String> = routes.iter().filter(|(method, path)| {
+			let response = client.req(*method, path.as_str())
+				.header(ContentType::JSON)
+				.body(r#"{ "username": "device", "tag": "tag", "name": "name" }"#)
+				.dispatch();
+			response.status() != Status::Unauthorized
+		}).map(|(_, path)| path).collect();
+		assert!(unguarded.is_empty(), "Device routes missing the AuthenticatedUser guard: {:?}", unguarded);
+	}
+
+	// Needs a MongoDB server at MONGO_URL (default mongodb://localhost): `cargo test -- --ignored`
+	#[test]
+	#[ignore]
+	fn prepares_databases_with_duplicate_users() {
+		use mongodb::{ ThreadedClient, db::ThreadedDatabase, doc };
+		use wither::model::Model;
+		use crate::models::{ User, Role };
+
+		let mongo_url = std::env::var("MONGO_URL").unwrap_or("mongodb://localhost".to_owned());
+		let db_name = format!("checkin-embedded-test-{}", hex::encode(rand::random::<[u8; 8]>()));
+		let db = mongodb::Client::with_uri(&mongo_url).unwrap().db(&db_name);
+		// What logging in twice with an older version left behind
+		for &role in &[Role::Viewer, Role::DeviceAdmin, Role::Viewer] {
+			let mut user = User { id: None, username: "george".to_owned(), role, last_login: None };
+			user.save(db.clone(), None).unwrap();
+		}
+
+		super::prepare_database(&db).unwrap();
+		let users = User::find(db.clone(), Some(doc! { "username": "george" }), None).unwrap();
+		assert_eq!(users.len(), 1);
+		assert_eq!(users[0].role, Role::DeviceAdmin);
+		let mut duplicate = User { id: None, username: "george".to_owned(), role: Role::Viewer, last_login: None };
+		assert!(duplicate.save(db.clone(), None).is_err(), "Username index wasn't created");
+		// Starting again with the index in place works too
+		super::prepare_database(&db).unwrap();
+		db.drop_database().unwrap();
+	}
+
+	// Revoking a device relies on this to cut off a device that ignores being told. Needs admin credentials for a checkin2 instance
+	// in CHECKIN_USERNAME and CHECKIN_PASSWORD: `cargo test -- --ignored`
+	#[test]
+	#[ignore]
+	fn deleting_checkin_account_ends_its_sessions() {
+		use hackgt_nfc::api::CheckinAPI;
+
+		let admin = CheckinAPI::login(&std::env::var("CHECKIN_USERNAME").unwrap(), &std::env::var("CHECKIN_PASSWORD").unwrap()).unwrap();
+		let username = format!("checkin-embedded-test-{}", hex::encode(rand::random::<[u8; 8]>()));
+		let password = hex::encode(rand::random::<[u8; 16]>());
+		admin.add_user(&username, &password).unwrap();
+		let device = CheckinAPI::login(&username, &password).unwrap();
+		assert!(device.get_tags_names(false).is_ok());
+
+		admin.delete_user(&username).unwrap();
+		assert!(device.get_tags_names(false).is_err(), "Device session still works after its account was deleted");
+	}
+
+	#[test]
+	fn challenged_api_responses_are_signed() {
+		use crate::signing::{ self, ServerKey };
+		use ed25519_dalek::{ PublicKey, Signature };
+
+		let key = ServerKey::generate();
+		let public_key = PublicKey::from_bytes(&hex::decode(key.public_key()).unwrap()).unwrap();
+		let client = Client::new(rocket::ignite()
+			.attach(signing::ResponseSigner)
+			.mount("/api", super::api_routes())
+			.manage(key)
+		).unwrap();
+		let challenge = "00112233445566778899aabbccddeeff";
+
+		let mut response = client.get("/api/device/command-status?id=1")
+			.header(rocket::http::Header::new(signing::CHALLENGE_HEADER, challenge))
+			.dispatch();
+		let signature = response.headers().get_one(signing::SIGNATURE_HEADER).map(str::to_owned).expect("Response wasn't signed");
+		let signature = Signature::from_bytes(&hex::decode(signature).unwrap()).unwrap();
+		let body = response.body_bytes().unwrap_or_default();
+		let status = response.status().code;
+		let no_body = signing::RequestBodyHash::of(b"").0;
+		let message = signing::response_message(challenge, "GET", "/api/device/command-status?id=1", &no_body, status, &body);
+		assert!(public_key.verify(&message, &signature).is_ok());
+		// Bound to the challenge and the request so it can't be replayed to another request
+		let replayed = signing::response_message("ffeeddccbbaa99887766554433221100", "GET", "/api/device/command-status?id=1", &no_body, status, &body);
+		assert!(public_key.verify(&replayed, &signature).is_err());
+		let other_path = signing::response_message(challenge, "GET", "/api/device/command-status?id=2", &no_body, status, &body);
+		assert!(public_key.verify(&other_path, &signature).is_err());
+		let other_method = signing::response_message(challenge, "POST", "/api/device/command-status?id=1", &no_body, status, &body);
+		assert!(public_key.verify(&other_method, &signature).is_err());
+
+		let response = client.get("/api/device/command-status").dispatch();
+		assert!(response.headers().get_one(signing::SIGNATURE_HEADER).is_none());
+		// Builds are checked against the release key instead
+		let response = client.get("/api/releases/1.0.0")
+			.header(rocket::http::Header::new(signing::CHALLENGE_HEADER, challenge))
+			.dispatch();
+		assert!(response.headers().get_one(signing::SIGNATURE_HEADER).is_none());
+	}
+
+	#[test]
+	fn oversized_signed_requests_are_turned_away() {
+		let client = Client::new(rocket::ignite().mount("/api", super::api_routes())).unwrap();
+		let response = client.post("/api/initialize")
+			.header(ContentType::JSON)
+			.header(rocket::http::Header::new("Authorization", "ed25519 00/00"))
+			.body(vec![b' '; 1024 * 1024])
+			.dispatch();
+		assert_eq!(response.status(), Status::PayloadTooLarge);
+	}
+}
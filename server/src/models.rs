@@ -1,4 +1,5 @@
 use serde::{ Serialize, Deserialize };
+use chrono::{ DateTime, Utc };
 use mongodb::{
 	coll::options::IndexModel,
 	oid::ObjectId,
@@ -23,6 +24,152 @@ pub struct Device {
 	pub credentials_created: bool,
 
 	pub current_tag: Option<String>,
+
+	// Reported by the device in its heartbeats
+	#[serde(default)]
+	pub last_seen: Option<DateTime<Utc>>,
+	#[serde(default)]
+	pub uptime: Option<i64>,
+	#[serde(default)]
+	pub client_version: Option<String>,
+	#[serde(default)]
+	pub reader_connected: Option<bool>,
+	#[serde(default)]
+	pub reported_tag: Option<String>,
+	/// Set once an offline alert has gone out so that we only send one per outage
+	#[serde(default)]
+	pub offline_alerted: bool,
+
+	/// Pushed to the device along with tag changes
+	#[serde(default)]
+	pub settings: DeviceSettings,
+}
+
+impl Device {
+	/// Devices send a heartbeat every 30 seconds so give them some slack before calling them offline
+	pub const ONLINE_TIMEOUT_SECONDS: i64 = 90;
+
+	/// Devices are named after their key, the same way they name their checkin2 accounts
+	pub fn username_for(public_key: &[u8]) -> String {
+		crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, public_key)
+	}
+
+	/// The six digit code a pending device scrolls on its displays. It has to be typed in to authorize the device so that nobody approves the wrong box.
+	/// Must match `Signer::get_pairing_code` in the client.
+	pub fn pairing_code(&self) -> Option<String> {
+		let mut data = b"checkin-embedded pairing code ".to_vec();
+		data.extend(hex::decode(&self.public_key).ok()?);
+		let hash = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &data);
+		let code = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+		Some(format!("{:06}", code))
+	}
+
+	pub fn is_online(&self) -> bool {
+		match self.last_seen {
+			Some(last_seen) => Utc::now().signed_duration_since(last_seen).num_seconds() < Device::ONLINE_TIMEOUT_SECONDS,
+			None => false,
+		}
+	}
+}
+
+/// Sets of buzzer tones a device can use for its feedback
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ToneTheme {
+	Classic,
+	/// An octave higher, easier to hear in a loud room
+	High,
+	/// An octave lower
+	Low,
+}
+
+impl ToneTheme {
+	pub const ALL: [ToneTheme; 3] = [ToneTheme::Classic, ToneTheme::High, ToneTheme::Low];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ToneTheme::Classic => "Classic",
+			ToneTheme::High => "High",
+			ToneTheme::Low => "Low",
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DeviceSettings {
+	/// 0 (dimmest) to 15
+	pub brightness: u8,
+	/// Milliseconds per column when scrolling text
+	pub scroll_speed: u32,
+	pub buzzer_enabled: bool,
+	pub tone_theme: ToneTheme,
+	/// Seconds between tag checks and heartbeats. None leaves the interval from the device's checkin.toml in place
+	pub poll_interval: Option<u32>,
+	/// Scrolled when the device has been idle for a while
+	pub idle_message: Option<String>,
+}
+
+impl Default for DeviceSettings {
+	fn default() -> Self {
+		Self {
+			brightness: 15,
+			scroll_speed: 5,
+			buzzer_enabled: true,
+			tone_theme: ToneTheme::Classic,
+			poll_interval: None,
+			idle_message: None,
+		}
+	}
+}
+
+impl DeviceSettings {
+	pub const MAX_BRIGHTNESS: u8 = 15;
+	pub const MAX_SCROLL_SPEED: u32 = 100;
+	pub const MIN_POLL_INTERVAL: u32 = 5;
+	// Heartbeats are sent on the same interval so anything longer would make the device look offline
+	pub const MAX_POLL_INTERVAL: u32 = Device::ONLINE_TIMEOUT_SECONDS as u32 / 2;
+
+	pub fn validate(&self) -> Result<(), String> {
+		if self.brightness > DeviceSettings::MAX_BRIGHTNESS {
+			return Err(format!("Brightness must be from 0 to {}", DeviceSettings::MAX_BRIGHTNESS));
+		}
+		if self.scroll_speed == 0 || self.scroll_speed > DeviceSettings::MAX_SCROLL_SPEED {
+			return Err(format!("Scroll speed must be from 1 to {} ms per column", DeviceSettings::MAX_SCROLL_SPEED));
+		}
+		if let Some(poll_interval) = self.poll_interval {
+			if poll_interval < DeviceSettings::MIN_POLL_INTERVAL || poll_interval > DeviceSettings::MAX_POLL_INTERVAL {
+				return Err(format!("Poll interval must be from {} to {} seconds", DeviceSettings::MIN_POLL_INTERVAL, DeviceSettings::MAX_POLL_INTERVAL));
+			}
+		}
+		Ok(())
+	}
+}
+
+// Ordered from least to most privileged so that roles can be compared with >=
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+	/// Can see devices but not change anything
+	Viewer,
+	/// Can also change the check-in tag of authorized devices
+	TagOperator,
+	/// Full control of devices and other users' roles
+	DeviceAdmin,
+}
+
+impl Role {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Role::Viewer => "Viewer",
+			Role::TagOperator => "TagOperator",
+			Role::DeviceAdmin => "DeviceAdmin",
+		}
+	}
+}
+
+impl Default for Role {
+	fn default() -> Self {
+		Role::Viewer
+	}
 }
 
 #[derive(Model, Serialize, Deserialize)]
@@ -30,6 +177,190 @@ pub struct User {
 	#[serde(rename="_id", skip_serializing_if="Option::is_none")]
     pub id: Option<ObjectId>,
 
+	#[model(index(index="dsc", unique="true"))]
+	pub username: String,
+	#[serde(default)]
+	pub role: Role,
+	#[serde(default)]
+	pub last_login: Option<DateTime<Utc>>,
+}
+
+impl User {
+	pub fn has_role(&self, role: Role) -> bool {
+		self.role >= role
+	}
+}
+
+#[derive(Model, Serialize, Deserialize)]
+pub struct Session {
+	#[serde(rename="_id", skip_serializing_if="Option::is_none")]
+	pub id: Option<ObjectId>,
+
+	#[model(index(index="dsc", unique="true"))]
+	pub session_id: String,
 	pub username: String,
-	pub auth_token: String,
+	pub ip_address: String,
+
+	pub created: DateTime<Utc>,
+	pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AuditAction {
+	Authorize,
+	Reject,
+	ForceRenew,
+	Delete,
+	SetTag,
+	Rename,
+	CreateCredentials,
+	SetRole,
+	ChangeSettings,
+	SendCommand,
+	RotateKey,
+}
+
+impl AuditAction {
+	pub const ALL: [AuditAction; 11] = [
+		AuditAction::Authorize,
+		AuditAction::Reject,
+		AuditAction::ForceRenew,
+		AuditAction::Delete,
+		AuditAction::SetTag,
+		AuditAction::Rename,
+		AuditAction::CreateCredentials,
+		AuditAction::SetRole,
+		AuditAction::ChangeSettings,
+		AuditAction::SendCommand,
+		AuditAction::RotateKey,
+	];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			AuditAction::Authorize => "Authorize",
+			AuditAction::Reject => "Reject",
+			AuditAction::ForceRenew => "ForceRenew",
+			AuditAction::Delete => "Delete",
+			AuditAction::SetTag => "SetTag",
+			AuditAction::Rename => "Rename",
+			AuditAction::CreateCredentials => "CreateCredentials",
+			AuditAction::SetRole => "SetRole",
+			AuditAction::ChangeSettings => "ChangeSettings",
+			AuditAction::SendCommand => "SendCommand",
+			AuditAction::RotateKey => "RotateKey",
+		}
+	}
+}
+
+#[derive(Model, Serialize, Deserialize)]
+pub struct AuditEvent {
+	#[serde(rename="_id", skip_serializing_if="Option::is_none")]
+	pub id: Option<ObjectId>,
+
+	pub action: AuditAction,
+	/// Username of the manager user (or device, for device-initiated actions) responsible
+	pub actor: String,
+	/// Username and friendly name of the device acted on at the time of the event
+	pub device: Option<String>,
+	pub device_name: Option<String>,
+
+	pub old_value: Option<String>,
+	pub new_value: Option<String>,
+
+	pub ip_address: String,
+	#[model(index(index="dsc"))]
+	pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CommandKind {
+	/// Flash, beep and scroll the device's name so it can be found
+	Identify,
+	Reboot,
+	/// Scroll an operator's message
+	ShowMessage,
+	/// Recreate the device's checkin2 account and log in again
+	RefetchCredentials,
+	/// Replace the device's key (and so its username and checkin2 account) after a suspected compromise
+	RotateKey,
+}
+
+impl CommandKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			CommandKind::Identify => "Identify",
+			CommandKind::Reboot => "Reboot",
+			CommandKind::ShowMessage => "ShowMessage",
+			CommandKind::RefetchCredentials => "RefetchCredentials",
+			CommandKind::RotateKey => "RotateKey",
+		}
+	}
+
+	/// Harmless commands can be sent by anyone running check-in
+	pub fn required_role(&self) -> Role {
+		match self {
+			CommandKind::Identify | CommandKind::ShowMessage => Role::TagOperator,
+			CommandKind::Reboot | CommandKind::RefetchCredentials | CommandKind::RotateKey => Role::DeviceAdmin,
+		}
+	}
+}
+
+/// A command sent to a device from the manager UI. Delivered over the updates long-poll until the device acknowledges it.
+#[derive(Model, Serialize, Deserialize, Clone)]
+pub struct DeviceCommand {
+	#[serde(rename="_id", skip_serializing_if="Option::is_none")]
+	pub id: Option<ObjectId>,
+
+	/// Username of the device
+	#[model(index(index="dsc"))]
+	pub device: String,
+	pub command: CommandKind,
+	pub message: Option<String>,
+
+	pub issued_by: String,
+	pub issued_at: DateTime<Utc>,
+	pub delivered_at: Option<DateTime<Utc>>,
+	pub acknowledged_at: Option<DateTime<Utc>>,
+	/// Reported by the device when acknowledging
+	pub success: Option<bool>,
+	pub details: Option<String>,
+}
+
+impl DeviceCommand {
+	/// Commands that haven't been picked up by then are dropped so that a kiosk doesn't reboot hours later
+	pub const EXPIRY_MINUTES: i64 = 10;
+
+	pub fn is_expired(&self) -> bool {
+		Utc::now().signed_duration_since(self.issued_at).num_minutes() >= DeviceCommand::EXPIRY_MINUTES
+	}
+
+	pub fn status(&self) -> &'static str {
+		match (self.acknowledged_at, self.success) {
+			(Some(_), Some(false)) => "Failed",
+			(Some(_), _) => "Acknowledged",
+			(None, _) if self.is_expired() => "Expired",
+			(None, _) if self.delivered_at.is_some() => "Delivered",
+			(None, _) => "Pending",
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pairing_code_matches_devices() {
+		// Public key for a secret key of all 7s, same as `pairing_code_matches_the_manager` in the client's crypto.rs
+		let device: Device = serde_json::from_value(serde_json::json!({
+			"public_key": "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c",
+			"friendly_name": "test",
+			"username": "test",
+			"ip_address": "127.0.0.1",
+			"authorized": false,
+			"pending": true,
+			"credentials_created": false,
+		})).unwrap();
+		assert_eq!(device.pairing_code(), Some("420191".to_owned()));
+	}
 }
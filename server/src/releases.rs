@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::PathBuf;
+use serde::{ Serialize, Deserialize };
+
+pub const BINARY_NAME: &'static str = "checkin-embedded-client";
+
+/// Describes a client build. Written next to the binary by `checkin-embedded-client --sign-release` and passed along to devices unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Release {
+    pub version: String,
+    /// Hex SHA-256 of the binary
+    pub sha256: String,
+    /// Hex ed25519 signature over the version and hash, made offline with the release key. We never see that key so a compromised manager can't push its own builds.
+    pub signature: String,
+}
+
+/// Client builds that devices can update to. Each release is a directory in RELEASES_DIR (default ./releases)
+/// named after its version that contains the binary and its `release.json`.
+pub struct Releases {
+    dir: PathBuf,
+}
+
+impl Releases {
+    pub fn new() -> Self {
+        let dir = std::env::var("RELEASES_DIR").unwrap_or("./releases".to_owned());
+        Self { dir: PathBuf::from(dir) }
+    }
+
+    // Releases are read every time so that new ones can be dropped in without restarting the manager
+    fn all(&self) -> Vec<Release> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let file = fs::read_to_string(path.join("release.json")).ok()?;
+            let release: Release = match serde_json::from_str(&file) {
+                Ok(release) => release,
+                Err(err) => {
+                    eprintln!("Invalid release in {}: {:?}", path.display(), err);
+                    return None;
+                }
+            };
+            // The directory name is what the download route looks up
+            if path.file_name()? != release.version.as_str() || !path.join(BINARY_NAME).is_file() {
+                eprintln!("Release in {} doesn't match its directory", path.display());
+                return None;
+            }
+            Some(release)
+        }).collect()
+    }
+
+    /// The newest release if it's newer than what the device is running
+    pub fn newer_than(&self, version: &str) -> Option<Release> {
+        self.all().into_iter()
+            .filter(|release| compare_versions(&release.version, version) == Some(Ordering::Greater))
+            .max_by(|a, b| compare_versions(&a.version, &b.version).unwrap_or(Ordering::Equal))
+    }
+
+    pub fn binary(&self, version: &str) -> Option<PathBuf> {
+        // Versions come from the URL so don't let them escape the releases directory
+        if parse_version(version).is_none() {
+            return None;
+        }
+        let path = self.dir.join(version).join(BINARY_NAME);
+        if path.is_file() { Some(path) } else { None }
+    }
+}
+
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Compares dotted version numbers like 0.1.10 and 0.2.0. Returns None if either isn't one.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    Some(parse_version(a)?.cmp(&parse_version(b)?))
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::io::{ Cursor, Write };
+use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+use rand::rngs::OsRng;
+use ed25519_dalek::Keypair;
+use rocket::{ Request, Response, State };
+use rocket::fairing::{ Fairing, Info, Kind };
+
+/// Random value devices send with each request so that a signed response can't be replayed to a different request
+pub const CHALLENGE_HEADER: &'static str = "X-Checkin-Challenge";
+pub const SIGNATURE_HEADER: &'static str = "X-Manager-Signature";
+
+/// The manager's own identity, used to sign data that devices receive through us
+pub struct ServerKey {
+    keypair: Keypair,
+}
+
+impl ServerKey {
+    /// Loads the key from SERVER_KEY_FILE (default ./server.key), generating a new one if it doesn't exist
+    pub fn load() -> Self {
+        let path = std::env::var("SERVER_KEY_FILE").unwrap_or("./server.key".to_owned());
+        ServerKey::load_from(&path)
+    }
+
+    // Only the manager's own user can read the key, same as the devices' key files
+    fn load_from(path: &str) -> Self {
+        let keypair = match fs::read(path) {
+            Ok(file) => {
+                let permissions = fs::metadata(path).expect("Error reading server key permissions").permissions();
+                if permissions.mode() & 0o077 != 0 {
+                    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).expect("Error restricting server key permissions");
+                }
+                Keypair::from_bytes(&file).expect("Invalid server key")
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = ServerKey::generate().keypair;
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path).expect("Error creating server key file");
+                file.write_all(&key.to_bytes()[..]).expect("Error writing server key to file");
+                key
+            },
+            Err(err) => panic!("There was an error opening the server key file: {:?}", err),
+        };
+        Self { keypair }
+    }
+
+    pub fn generate() -> Self {
+        let mut csprng = OsRng::new().unwrap();
+        Self { keypair: Keypair::generate(&mut csprng) }
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(&self.keypair.public.to_bytes()[..])
+    }
+
+    /// Returns a hex encoded signature
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(&self.keypair.sign(message).to_bytes()[..])
+    }
+}
+
+/// Hex SHA-256 of a device's request body. Cached on the request by the SignedRequest guard for the response signature.
+pub struct RequestBodyHash(pub String);
+
+impl RequestBodyHash {
+    pub fn of(body: &[u8]) -> Self {
+        RequestBodyHash(crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, body))
+    }
+}
+
+/// What gets signed for a response. Must match `response_message` in the client.
+/// Covers the request (`path` includes the query) so that a response can't be passed off as the answer to a different one,
+/// e.g. another device's tag. The prefix keeps these signatures from being mistaken for the data the manager signs for devices.
+pub fn response_message(challenge: &str, method: &str, path: &str, body_hash: &str, status: u16, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("checkin-embedded response {} {} {} {} {}\n", challenge, method, path, body_hash, status).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// Signs API responses to requests that carry a challenge so that devices can tell they really came from us
+/// and not from someone in the middle redirecting their tags. Release binaries are left alone: they carry their own
+/// signature from the release key and signing them would mean holding each one in memory.
+pub struct ResponseSigner;
+
+impl Fairing for ResponseSigner {
+    fn info(&self) -> Info {
+        Info {
+            name: "Device response signer",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let path = request.uri().path();
+        if !path.starts_with("/api/") || path.starts_with("/api/releases/") {
+            return;
+        }
+        let challenge = match request.headers().get_one(CHALLENGE_HEADER) {
+            // Don't sign whatever anyone sends us
+            Some(challenge) if challenge.len() == 32 && challenge.chars().all(|c| c.is_ascii_hexdigit()) => challenge,
+            _ => return,
+        };
+        let key = match request.guard::<State<ServerKey>>().succeeded() {
+            Some(key) => key,
+            None => return,
+        };
+        // Requests without a body never go through the guard
+        let request_body_hash = request.local_cache(|| RequestBodyHash::of(b""));
+        let path = match request.uri().query() {
+            Some(query) => format!("{}?{}", request.uri().path(), query),
+            None => request.uri().path().to_owned(),
+        };
+        let body = response.body_bytes().unwrap_or_default();
+        let message = response_message(challenge, request.method().as_str(), &path, &request_body_hash.0, response.status().code, &body);
+        let signature = key.sign(&message);
+        response.set_sized_body(Cursor::new(body));
+        response.set_raw_header(SIGNATURE_HEADER, signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(path: &str) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn server_key_is_private_to_the_manager() {
+        let path = std::env::temp_dir().join(format!("checkin-server-key-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let key = ServerKey::load_from(path);
+        assert_eq!(mode(path), 0o600);
+        assert_eq!(ServerKey::load_from(path).public_key(), key.public_key());
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(ServerKey::load_from(path).public_key(), key.public_key());
+        assert_eq!(mode(path), 0o600);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -62,6 +62,8 @@ function setupButtonHandlers(className, handler) {
         }); });
     }
 }
+// Sent with every request so that the server can verify it came from this page
+var csrfToken = document.querySelector("meta[name=csrf-token]").content;
 function makeButtonRequest(url, username) {
     return __awaiter(this, void 0, void 0, function () {
         return __generator(this, function (_a) {
@@ -69,7 +71,8 @@ function makeButtonRequest(url, username) {
                     method: "POST",
                     credentials: "include",
                     headers: {
-                        "Content-Type": "application/json"
+                        "Content-Type": "application/json",
+                        "X-CSRF-Token": csrfToken
                     },
                     body: JSON.stringify({ username: username })
                 }).then(function (response) { return response.json(); })];
@@ -77,10 +80,22 @@ function makeButtonRequest(url, username) {
     });
 }
 setupButtonHandlers("action-authorize", function (id) { return __awaiter(_this, void 0, void 0, function () {
-    var response;
+    var pairingCode, response;
     return __generator(this, function (_a) {
         switch (_a.label) {
-            case 0: return [4 /*yield*/, makeButtonRequest("/api/device/authorize", id)];
+            case 0:
+                pairingCode = prompt("Pairing code shown on the device:");
+                if (!pairingCode)
+                    return [2 /*return*/];
+                return [4 /*yield*/, fetch("/api/device/authorize", {
+                        method: "POST",
+                        credentials: "include",
+                        headers: {
+                            "Content-Type": "application/json",
+                            "X-CSRF-Token": csrfToken
+                        },
+                        body: JSON.stringify({ username: id, pairing_code: pairingCode })
+                    }).then(function (response) { return response.json(); })];
             case 1:
                 response = _a.sent();
                 if (response.success) {
@@ -156,7 +171,8 @@ setupButtonHandlers("action-rename", function (id) { return __awaiter(_this, voi
                         method: "POST",
                         credentials: "include",
                         headers: {
-                            "Content-Type": "application/json"
+                            "Content-Type": "application/json",
+                            "X-CSRF-Token": csrfToken
                         },
                         body: JSON.stringify({ username: id, name: name })
                     }).then(function (response) { return response.json(); })];
@@ -172,6 +188,142 @@ setupButtonHandlers("action-rename", function (id) { return __awaiter(_this, voi
         }
     });
 }); });
+function showCommandStatus(username, text) {
+    var status = document.querySelector(".command-status[data-username=\"" + username + "\"]");
+    if (status) {
+        status.innerHTML = "";
+        var code = document.createElement("code");
+        code.title = "Last command";
+        code.textContent = text;
+        status.appendChild(code);
+    }
+}
+function sleep(milliseconds) {
+    return new Promise(function (resolve) { return setTimeout(resolve, milliseconds); });
+}
+// Shows the command's progress until the device acknowledges it or it expires (10 minutes)
+function watchCommand(username, command, id) {
+    return __awaiter(this, void 0, void 0, function () {
+        var i, response;
+        return __generator(this, function (_a) {
+            switch (_a.label) {
+                case 0:
+                    i = 0;
+                    _a.label = 1;
+                case 1:
+                    if (!(i < 300)) return [3 /*break*/, 5];
+                    return [4 /*yield*/, fetch("/api/device/command-status?id=" + id, {
+                            credentials: "include"
+                        }).then(function (response) { return response.json(); })];
+                case 2:
+                    response = _a.sent();
+                    if (!response.success) {
+                        showCommandStatus(username, command + " Unknown");
+                        return [2 /*return*/];
+                    }
+                    showCommandStatus(username, command + " " + response.status);
+                    if (response.status === "Failed") {
+                        alert(command + " failed on the device (" + (response.details || "No details") + ")");
+                        return [2 /*return*/];
+                    }
+                    if (response.status === "Acknowledged" || response.status === "Expired") {
+                        return [2 /*return*/];
+                    }
+                    return [4 /*yield*/, sleep(2000)];
+                case 3:
+                    _a.sent();
+                    _a.label = 4;
+                case 4:
+                    i++;
+                    return [3 /*break*/, 1];
+                case 5: return [2 /*return*/];
+            }
+        });
+    });
+}
+function sendCommand(username, command, message) {
+    return __awaiter(this, void 0, void 0, function () {
+        var response;
+        return __generator(this, function (_a) {
+            switch (_a.label) {
+                case 0: return [4 /*yield*/, fetch("/api/device/command", {
+                        method: "POST",
+                        credentials: "include",
+                        headers: {
+                            "Content-Type": "application/json",
+                            "X-CSRF-Token": csrfToken
+                        },
+                        body: JSON.stringify({ username: username, command: command, message: message })
+                    }).then(function (response) { return response.json(); })];
+                case 1:
+                    response = _a.sent();
+                    if (response.success && response.id) {
+                        // Not awaited so that the button can be used again while waiting on the device
+                        watchCommand(username, command, response.id);
+                    }
+                    else {
+                        alert(response.error + " (" + (response.details || "No details") + ")");
+                    }
+                    return [2 /*return*/];
+            }
+        });
+    });
+}
+setupButtonHandlers("action-identify", function (id) { return sendCommand(id, "Identify"); });
+setupButtonHandlers("action-message", function (id) { return __awaiter(_this, void 0, void 0, function () {
+    var message;
+    return __generator(this, function (_a) {
+        switch (_a.label) {
+            case 0:
+                message = prompt("Message to show:");
+                if (!message)
+                    return [2 /*return*/];
+                return [4 /*yield*/, sendCommand(id, "ShowMessage", message)];
+            case 1:
+                _a.sent();
+                return [2 /*return*/];
+        }
+    });
+}); });
+setupButtonHandlers("action-reboot", function (id) { return __awaiter(_this, void 0, void 0, function () {
+    return __generator(this, function (_a) {
+        switch (_a.label) {
+            case 0:
+                if (!confirm("Reboot this device?"))
+                    return [2 /*return*/];
+                return [4 /*yield*/, sendCommand(id, "Reboot")];
+            case 1:
+                _a.sent();
+                return [2 /*return*/];
+        }
+    });
+}); });
+setupButtonHandlers("action-refetch-credentials", function (id) { return __awaiter(_this, void 0, void 0, function () {
+    return __generator(this, function (_a) {
+        switch (_a.label) {
+            case 0:
+                if (!confirm("Delete this device's check-in account and have it log in again?"))
+                    return [2 /*return*/];
+                return [4 /*yield*/, sendCommand(id, "RefetchCredentials")];
+            case 1:
+                _a.sent();
+                return [2 /*return*/];
+        }
+    });
+}); });
+setupButtonHandlers("action-rotate-key", function (id) { return __awaiter(_this, void 0, void 0, function () {
+    return __generator(this, function (_a) {
+        switch (_a.label) {
+            case 0:
+                if (!confirm("Have this device replace its key and check-in account? Do this if the device might have been compromised."))
+                    return [2 /*return*/];
+                return [4 /*yield*/, sendCommand(id, "RotateKey")];
+            case 1:
+                _a.sent();
+                return [2 /*return*/];
+        }
+    });
+}); });
 var selects = document.getElementsByClassName("tag-select");
 for (var i = 0; i < selects.length; i++) {
     selects[i].addEventListener("change", function (e) { return __awaiter(_this, void 0, void 0, function () {
@@ -187,7 +339,8 @@ for (var i = 0; i < selects.length; i++) {
                             method: "POST",
                             credentials: "include",
                             headers: {
-                                "Content-Type": "application/json"
+                                "Content-Type": "application/json",
+                                "X-CSRF-Token": csrfToken
                             },
                             body: JSON.stringify({ username: deviceUsername, tag: select.value })
                         }).then(function (response) { return response.json(); })];
@@ -203,3 +356,104 @@ for (var i = 0; i < selects.length; i++) {
         });
     }); });
 }
+var roleSelects = document.getElementsByClassName("role-select");
+for (var i_1 = 0; i_1 < roleSelects.length; i_1++) {
+    roleSelects[i_1].addEventListener("change", function (e) { return __awaiter(_this, void 0, void 0, function () {
+        var select, username, response;
+        return __generator(this, function (_a) {
+            switch (_a.label) {
+                case 0:
+                    select = e.target;
+                    username = select.dataset.username;
+                    if (!username) return [3 /*break*/, 2];
+                    select.disabled = true;
+                    return [4 /*yield*/, fetch("/api/user/set-role", {
+                            method: "POST",
+                            credentials: "include",
+                            headers: {
+                                "Content-Type": "application/json",
+                                "X-CSRF-Token": csrfToken
+                            },
+                            body: JSON.stringify({ username: username, role: select.value })
+                        }).then(function (response) { return response.json(); })];
+                case 1:
+                    response = _a.sent();
+                    if (!response.success) {
+                        alert(response.error + " (" + (response.details || "No details") + ")");
+                    }
+                    select.disabled = false;
+                    _a.label = 2;
+                case 2: return [2 /*return*/];
+            }
+        });
+    }); });
+}
+// Bulk editing device settings
+var selectAllDevices = document.getElementById("select-all-devices");
+var deviceCheckboxes = document.getElementsByClassName("device-select");
+if (selectAllDevices) {
+    selectAllDevices.addEventListener("change", function (e) {
+        var checked = e.target.checked;
+        for (var i = 0; i < deviceCheckboxes.length; i++) {
+            deviceCheckboxes[i].checked = checked;
+        }
+    });
+}
+function formValue(form, name) {
+    return form.elements.namedItem(name).value.trim();
+}
+function numberOrUndefined(value) {
+    return value ? parseInt(value, 10) : undefined;
+}
+var settingsForm = document.getElementById("settings-form");
+if (settingsForm) {
+    settingsForm.addEventListener("submit", function (e) { return __awaiter(_this, void 0, void 0, function () {
+        var form, usernames, i, buzzer, idleMessage, clearIdleMessage, settings, response;
+        return __generator(this, function (_a) {
+            switch (_a.label) {
+                case 0:
+                    e.preventDefault();
+                    form = e.target;
+                    usernames = [];
+                    for (i = 0; i < deviceCheckboxes.length; i++) {
+                        if (deviceCheckboxes[i].checked) {
+                            usernames.push(deviceCheckboxes[i].value);
+                        }
+                    }
+                    if (usernames.length === 0) {
+                        alert("Select at least one device first");
+                        return [2 /*return*/];
+                    }
+                    buzzer = formValue(form, "buzzer_enabled");
+                    idleMessage = formValue(form, "idle_message");
+                    clearIdleMessage = form.elements.namedItem("clear_idle_message").checked;
+                    settings = {
+                        brightness: numberOrUndefined(formValue(form, "brightness")),
+                        scroll_speed: numberOrUndefined(formValue(form, "scroll_speed")),
+                        buzzer_enabled: buzzer ? buzzer === "true" : undefined,
+                        tone_theme: formValue(form, "tone_theme") || undefined,
+                        poll_interval: numberOrUndefined(formValue(form, "poll_interval")),
+                        idle_message: clearIdleMessage ? "" : (idleMessage || undefined),
+                    };
+                    return [4 /*yield*/, fetch("/api/device/settings", {
+                            method: "POST",
+                            credentials: "include",
+                            headers: {
+                                "Content-Type": "application/json",
+                                "X-CSRF-Token": csrfToken
+                            },
+                            body: JSON.stringify({ usernames: usernames, settings: settings })
+                        }).then(function (response) { return response.json(); })];
+                case 1:
+                    response = _a.sent();
+                    if (response.success) {
+                        window.location.reload();
+                    }
+                    else {
+                        alert(response.error + " (" + (response.details || "No details") + ")");
+                    }
+                    return [2 /*return*/];
+            }
+        });
+    }); });
+}
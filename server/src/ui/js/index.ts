@@ -17,6 +17,9 @@ function setupButtonHandlers(className: string, handler: (id: string) => Promise
 	}
 }
 
+// Sent with every request so that the server can verify it came from this page
+const csrfToken = (document.querySelector("meta[name=csrf-token]") as HTMLMetaElement).content;
+
 interface APIResponse {
 	success?: boolean,
 	error?: string,
@@ -27,14 +30,26 @@ async function makeButtonRequest(url: string, username: string): Promise<APIResp
 		method: "POST",
 		credentials: "include",
 		headers: {
-			"Content-Type": "application/json"
+			"Content-Type": "application/json",
+			"X-CSRF-Token": csrfToken
 		},
 		body: JSON.stringify({ username })
 	}).then(response => response.json());
 }
 
 setupButtonHandlers("action-authorize", async id => {
-	let response = await makeButtonRequest("/api/device/authorize", id);
+	// Shown on the device's displays while it waits for approval so that the right one gets approved
+	let pairingCode = prompt("Pairing code shown on the device:");
+	if (!pairingCode) return;
+	let response: APIResponse = await fetch("/api/device/authorize", {
+		method: "POST",
+		credentials: "include",
+		headers: {
+			"Content-Type": "application/json",
+			"X-CSRF-Token": csrfToken
+		},
+		body: JSON.stringify({ username: id, pairing_code: pairingCode })
+	}).then(response => response.json());
 	if (response.success) {
 		window.location.reload();
 	}
@@ -76,7 +91,8 @@ setupButtonHandlers("action-rename", async id => {
 		method: "POST",
 		credentials: "include",
 		headers: {
-			"Content-Type": "application/json"
+			"Content-Type": "application/json",
+			"X-CSRF-Token": csrfToken
 		},
 		body: JSON.stringify({ username: id, name })
 	}).then(response => response.json());
@@ -88,6 +104,82 @@ setupButtonHandlers("action-rename", async id => {
 	}
 });
 
+// Remote commands
+interface CommandResponse extends APIResponse {
+	id?: string,
+	status?: string,
+}
+function showCommandStatus(username: string, text: string) {
+	let status = document.querySelector(`.command-status[data-username="${username}"]`);
+	if (status) {
+		status.innerHTML = "";
+		let code = document.createElement("code");
+		code.title = "Last command";
+		code.textContent = text;
+		status.appendChild(code);
+	}
+}
+function sleep(milliseconds: number): Promise<void> {
+	return new Promise(resolve => setTimeout(resolve, milliseconds));
+}
+// Shows the command's progress until the device acknowledges it or it expires (10 minutes)
+async function watchCommand(username: string, command: string, id: string) {
+	for (let i = 0; i < 300; i++) {
+		let response: CommandResponse = await fetch(`/api/device/command-status?id=${id}`, {
+			credentials: "include"
+		}).then(response => response.json());
+		if (!response.success) {
+			showCommandStatus(username, `${command} Unknown`);
+			return;
+		}
+		showCommandStatus(username, `${command} ${response.status}`);
+		if (response.status === "Failed") {
+			alert(`${command} failed on the device (${response.details || "No details"})`);
+			return;
+		}
+		if (response.status === "Acknowledged" || response.status === "Expired") {
+			return;
+		}
+		await sleep(2000);
+	}
+}
+async function sendCommand(username: string, command: string, message?: string) {
+	let response: CommandResponse = await fetch("/api/device/command", {
+		method: "POST",
+		credentials: "include",
+		headers: {
+			"Content-Type": "application/json",
+			"X-CSRF-Token": csrfToken
+		},
+		body: JSON.stringify({ username, command, message })
+	}).then(response => response.json());
+	if (response.success && response.id) {
+		// Not awaited so that the button can be used again while waiting on the device
+		watchCommand(username, command, response.id);
+	}
+	else {
+		alert(`${response.error} (${response.details || "No details"})`);
+	}
+}
+setupButtonHandlers("action-identify", id => sendCommand(id, "Identify"));
+setupButtonHandlers("action-message", async id => {
+	let message = prompt("Message to show:");
+	if (!message) return;
+	await sendCommand(id, "ShowMessage", message);
+});
+setupButtonHandlers("action-reboot", async id => {
+	if (!confirm("Reboot this device?")) return;
+	await sendCommand(id, "Reboot");
+});
+setupButtonHandlers("action-refetch-credentials", async id => {
+	if (!confirm("Delete this device's check-in account and have it log in again?")) return;
+	await sendCommand(id, "RefetchCredentials");
+});
+setupButtonHandlers("action-rotate-key", async id => {
+	if (!confirm("Have this device replace its key and check-in account? Do this if the device might have been compromised.")) return;
+	await sendCommand(id, "RotateKey");
+});
+
 let selects = document.getElementsByClassName("tag-select") as HTMLCollectionOf<HTMLSelectElement>;
 for (let i = 0; i < selects.length; i++) {
 	selects[i].addEventListener("change", async e => {
@@ -99,7 +191,8 @@ for (let i = 0; i < selects.length; i++) {
 				method: "POST",
 				credentials: "include",
 				headers: {
-					"Content-Type": "application/json"
+					"Content-Type": "application/json",
+					"X-CSRF-Token": csrfToken
 				},
 				body: JSON.stringify({ username: deviceUsername, tag: select.value })
 			}).then(response => response.json());
@@ -110,3 +203,91 @@ for (let i = 0; i < selects.length; i++) {
 		}
 	});
 }
+
+let roleSelects = document.getElementsByClassName("role-select") as HTMLCollectionOf<HTMLSelectElement>;
+for (let i = 0; i < roleSelects.length; i++) {
+	roleSelects[i].addEventListener("change", async e => {
+		let select = e.target as HTMLSelectElement;
+		let username = select.dataset.username;
+		if (username) {
+			select.disabled = true;
+			let response: APIResponse = await fetch("/api/user/set-role", {
+				method: "POST",
+				credentials: "include",
+				headers: {
+					"Content-Type": "application/json",
+					"X-CSRF-Token": csrfToken
+				},
+				body: JSON.stringify({ username, role: select.value })
+			}).then(response => response.json());
+			if (!response.success) {
+				alert(`${response.error} (${response.details || "No details"})`);
+			}
+			select.disabled = false;
+		}
+	});
+}
+
+// Bulk editing device settings
+let selectAllDevices = document.getElementById("select-all-devices") as HTMLInputElement | null;
+let deviceCheckboxes = document.getElementsByClassName("device-select") as HTMLCollectionOf<HTMLInputElement>;
+if (selectAllDevices) {
+	selectAllDevices.addEventListener("change", e => {
+		let checked = (e.target as HTMLInputElement).checked;
+		for (let i = 0; i < deviceCheckboxes.length; i++) {
+			deviceCheckboxes[i].checked = checked;
+		}
+	});
+}
+
+function formValue(form: HTMLFormElement, name: string): string {
+	return (form.elements.namedItem(name) as HTMLInputElement).value.trim();
+}
+function numberOrUndefined(value: string): number | undefined {
+	return value ? parseInt(value, 10) : undefined;
+}
+
+let settingsForm = document.getElementById("settings-form") as HTMLFormElement | null;
+if (settingsForm) {
+	settingsForm.addEventListener("submit", async e => {
+		e.preventDefault();
+		let form = e.target as HTMLFormElement;
+		let usernames: string[] = [];
+		for (let i = 0; i < deviceCheckboxes.length; i++) {
+			if (deviceCheckboxes[i].checked) {
+				usernames.push(deviceCheckboxes[i].value);
+			}
+		}
+		if (usernames.length === 0) {
+			alert("Select at least one device first");
+			return;
+		}
+		// Blank fields are left out so that they aren't changed
+		let buzzer = formValue(form, "buzzer_enabled");
+		let idleMessage = formValue(form, "idle_message");
+		let clearIdleMessage = (form.elements.namedItem("clear_idle_message") as HTMLInputElement).checked;
+		let settings = {
+			brightness: numberOrUndefined(formValue(form, "brightness")),
+			scroll_speed: numberOrUndefined(formValue(form, "scroll_speed")),
+			buzzer_enabled: buzzer ? buzzer === "true" : undefined,
+			tone_theme: formValue(form, "tone_theme") || undefined,
+			poll_interval: numberOrUndefined(formValue(form, "poll_interval")),
+			idle_message: clearIdleMessage ? "" : (idleMessage || undefined),
+		};
+		let response: APIResponse = await fetch("/api/device/settings", {
+			method: "POST",
+			credentials: "include",
+			headers: {
+				"Content-Type": "application/json",
+				"X-CSRF-Token": csrfToken
+			},
+			body: JSON.stringify({ usernames, settings })
+		}).then(response => response.json());
+		if (response.success) {
+			window.location.reload();
+		}
+		else {
+			alert(`${response.error} (${response.details || "No details"})`);
+		}
+	});
+}
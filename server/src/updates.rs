@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{ Condvar, Mutex };
+use std::time::{ Duration, Instant };
+
+/// Lets long-polling devices wait for changes made in the manager UI
+pub struct DeviceUpdates {
+    // Mixed into every version so that versions handed out before a server restart never match
+    instance: String,
+    versions: Mutex<HashMap<String, u64>>,
+    changed: Condvar,
+}
+
+impl DeviceUpdates {
+    pub fn new() -> Self {
+        Self {
+            instance: hex::encode(&rand::random::<[u8; 8]>()[..]),
+            versions: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn version(&self, versions: &HashMap<String, u64>, username: &str) -> String {
+        format!("{}-{}", self.instance, versions.get(username).cloned().unwrap_or(0))
+    }
+
+    /// Wakes up any requests waiting on this device
+    pub fn notify(&self, username: &str) {
+        let mut versions = self.versions.lock().unwrap();
+        *versions.entry(username.to_owned()).or_insert(0) += 1;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until the device's version no longer matches `known` or the timeout passes and returns the current version
+    pub fn wait(&self, username: &str, known: Option<&str>, timeout: Duration) -> String {
+        let deadline = Instant::now() + timeout;
+        let mut versions = self.versions.lock().unwrap();
+        loop {
+            let current = self.version(&versions, username);
+            let now = Instant::now();
+            if known != Some(current.as_str()) || now >= deadline {
+                return current;
+            }
+            versions = self.changed.wait_timeout(versions, deadline - now).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn wakes_waiting_devices_when_notified() {
+        let updates = Arc::new(DeviceUpdates::new());
+        let known = updates.wait("device", None, TIMEOUT);
+
+        let waiting = Arc::clone(&updates);
+        let known_by_thread = known.clone();
+        let start = Instant::now();
+        let waiter = thread::spawn(move || waiting.wait("device", Some(&known_by_thread), TIMEOUT));
+        thread::sleep(Duration::from_millis(50));
+        updates.notify("device");
+        let version = waiter.join().unwrap();
+        assert_ne!(version, known);
+        assert!(start.elapsed() < TIMEOUT);
+        assert_eq!(updates.wait("device", Some(&version), Duration::from_millis(0)), version);
+    }
+
+    #[test]
+    fn gives_up_after_the_timeout() {
+        let updates = DeviceUpdates::new();
+        let known = updates.wait("device", None, TIMEOUT);
+        updates.notify("another device");
+        let start = Instant::now();
+        assert_eq!(updates.wait("device", Some(&known), Duration::from_millis(50)), known);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn versions_from_before_a_restart_never_match() {
+        let known = DeviceUpdates::new().wait("device", None, TIMEOUT);
+        let start = Instant::now();
+        assert_ne!(DeviceUpdates::new().wait("device", Some(&known), TIMEOUT), known);
+        assert!(start.elapsed() < TIMEOUT);
+    }
+}